@@ -0,0 +1,82 @@
+use std::fmt;
+
+// Everything that can go wrong turning a firehose frame into graph writes.
+// The main loop decides what to do about each, handle_event never panics
+#[derive(Debug)]
+pub enum IngestError {
+    // The websocket itself failed
+    Connection(Box<tokio_tungstenite::tungstenite::Error>),
+    Decompress(String),
+    Decode(String),
+    // Valid JSON, but not the shape we expect for its collection
+    Schema(String),
+    // How many ms behind the firehose we are
+    Lag(i64),
+    Storage(neo4rs::Error),
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::Connection(e) => write!(f, "connection error: {e}"),
+            IngestError::Decompress(e) => write!(f, "error decompressing event: {e}"),
+            IngestError::Decode(e) => write!(f, "error decoding event: {e}"),
+            IngestError::Schema(e) => write!(f, "unexpected event: {e}"),
+            IngestError::Lag(drift) => {
+                write!(f, "{drift}ms late (probably need to speed up ingest)")
+            }
+            IngestError::Storage(e) => write!(f, "error writing to graph: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
+impl From<neo4rs::Error> for IngestError {
+    fn from(e: neo4rs::Error) -> Self {
+        IngestError::Storage(e)
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for IngestError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        IngestError::Connection(Box::new(e))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ErrorCounts {
+    pub connection: u64,
+    pub decompress: u64,
+    pub decode: u64,
+    pub schema: u64,
+    pub lag: u64,
+    pub storage: u64,
+}
+
+impl ErrorCounts {
+    pub fn record(&mut self, e: &IngestError) {
+        match e {
+            IngestError::Connection(_) => self.connection += 1,
+            IngestError::Decompress(_) => self.decompress += 1,
+            IngestError::Decode(_) => self.decode += 1,
+            IngestError::Schema(_) => self.schema += 1,
+            IngestError::Lag(_) => self.lag += 1,
+            IngestError::Storage(_) => self.storage += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.connection + self.decompress + self.decode + self.schema + self.lag + self.storage
+    }
+}
+
+impl fmt::Display for ErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connection: {}, decompress: {}, decode: {}, schema: {}, lag: {}, storage: {}",
+            self.connection, self.decompress, self.decode, self.schema, self.lag, self.storage
+        )
+    }
+}
@@ -1,38 +1,59 @@
 use crate::bsky::types::*;
-use crate::graph::GraphModel;
+use crate::common::{lang, AtUri};
+use crate::graph::{AccountState, GraphModel, PostContent, PostMedia};
 use chrono::Utc;
 use once_cell::sync::Lazy;
 use std::collections::HashSet;
 use std::mem;
+use std::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
 use zstd::bulk::Decompressor;
+pub mod error;
 mod types;
+pub mod xrpc;
 
-const DICT: &'static [u8; 112640] = include_bytes!("./dictionary");
-static mut DECOMP: Lazy<Decompressor<'static>> =
-    Lazy::new(|| zstd::bulk::Decompressor::with_dictionary(DICT).unwrap());
-
-fn decompress(m: tokio_tungstenite::tungstenite::Message) -> Option<BskyEvent> {
-    unsafe {
-        let msg = DECOMP.decompress(m.into_data().as_slice(), 1024000);
-        match msg {
-            Ok(m) => {
-                match serde_json::from_slice(m.as_slice()) {
-                    Ok(m) => return Some(m),
-                    Err(_err) => {
-                        panic!("1")
-                    }
-                };
-            }
-            Err(_) => panic!("2"),
-        };
+pub use error::{ErrorCounts, IngestError};
+pub use xrpc::{ClientError, XrpcClient};
+
+const DICT: &[u8; 112640] = include_bytes!("./dictionary");
+static DECOMP: Lazy<Mutex<Decompressor<'static>>> =
+    Lazy::new(|| Mutex::new(zstd::bulk::Decompressor::with_dictionary(DICT).unwrap()));
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>, IngestError> {
+    let mut decomp = match DECOMP.lock() {
+        Ok(d) => d,
+        Err(_) => return Err(IngestError::Decompress("decompressor poisoned".into())),
+    };
+    match decomp.decompress(data, 1024000) {
+        Ok(m) => Ok(m),
+        Err(e) => Err(IngestError::Decompress(e.to_string())),
     }
 }
 
+fn decode(data: &[u8]) -> Result<BskyEvent, IngestError> {
+    match serde_json::from_slice(data) {
+        Ok(m) => Ok(m),
+        // Well formed JSON that doesnt fit our types is a schema problem, not a broken frame
+        Err(e) if e.is_data() => Err(IngestError::Schema(e.to_string())),
+        Err(e) => Err(IngestError::Decode(e.to_string())),
+    }
+}
+
+// How far behind the firehose we can fall before giving up
+pub const MAX_DRIFT_MS: i64 = 30000;
+
+pub fn drift_ms(time_us: i64) -> i64 {
+    (Utc::now().naive_utc().and_utc().timestamp_micros() - time_us) / 1000
+}
+
+// Returns the time_us of the event once it has been handled, so the caller can checkpoint it.
+// While catching_up we are replaying from a cursor, so being behind is expected
 pub async fn handle_event(
-    evt: Result<tokio_tungstenite::tungstenite::Message, tokio_tungstenite::tungstenite::Error>,
+    evt: Result<Message, tokio_tungstenite::tungstenite::Error>,
     g: &mut GraphModel,
     compressed: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+    catching_up: bool,
+) -> Result<Option<i64>, IngestError> {
     let mut spam = HashSet::new();
     spam.insert("did:plc:xdx2v7gyd5dmfqt7v77gf457".to_owned());
     spam.insert("did:plc:a56vfzkrxo2bh443zgjxr4ix".to_owned());
@@ -44,167 +65,235 @@ pub async fn handle_event(
     spam.insert("did:plc:ss7fj6p6yfirwq2hnlkfuntt".to_owned());
     match evt {
         Ok(msg) => {
-            let deser_evt: BskyEvent;
-            if compressed {
-                deser_evt = decompress(msg).unwrap();
-            } else {
-                match serde_json::from_slice(msg.into_data().as_slice()) {
-                    Ok(m) => {
-                        deser_evt = m;
-                    }
-                    Err(_err) => {
-                        panic!("1")
-                    }
-                };
+            // Pings, pongs & closes are handled by tungstenite, nothing for us in them
+            if !msg.is_text() && !msg.is_binary() {
+                return Ok(None);
             }
+            let deser_evt = if compressed {
+                decode(&decompress(&msg.into_data())?)?
+            } else {
+                decode(&msg.into_data())?
+            };
 
+            let time_us = deser_evt.time_us;
             let commit: &Commit = match &deser_evt.commit {
                 Some(m) => m,
                 None => {
-                    return Ok(());
+                    handle_repo_event(&deser_evt, g).await?;
+                    return Ok(Some(time_us));
                 }
             };
             let rkey = commit.rkey.clone();
 
-            let drift =
-                (Utc::now().naive_utc().and_utc().timestamp_micros() - deser_evt.time_us) / 1000;
-            if drift > 30000 {
-                panic!("{drift}ms late (probably need to speed up ingest)!!!");
+            let drift = drift_ms(time_us);
+            if drift > MAX_DRIFT_MS && !catching_up {
+                return Err(IngestError::Lag(drift));
             }
             //println!("{drift}ms late");
             if spam.contains(&deser_evt.did) {
-                return Ok(());
+                return Ok(Some(time_us));
             }
             let now = Utc::now().timestamp_micros();
 
             if commit.operation == "create" {
-                let mut is_reply = false;
-                let mut is_image = false;
+                let mut reply = None;
+                let mut content = PostContent::default();
                 let mut created_at = 0;
                 match commit.collection.as_str() {
                     "app.bsky.feed.post" => {
-                        match &commit.record {
-                            Some(r) => {
-                                is_image = r.images.is_some();
-                                created_at =
-                                    match chrono::DateTime::parse_from_rfc3339(&r.created_at) {
-                                        Ok(t) => {
-                                            if now - t.timestamp_micros()
-                                                > chrono::Duration::hours(24)
-                                                    .num_microseconds()
-                                                    .unwrap()
-                                            {
-                                                return Ok(());
-                                            }
-                                            t.timestamp_micros()
-                                        }
-                                        Err(_) => deser_evt.time_us,
-                                    };
-                                match &r.reply {
-                                    Some(r) => {
-                                        let did_clone = deser_evt.did.clone();
-                                        let rkey_clone = rkey.clone();
-                                        let rkey_parent = parse_rkey(&r.parent.uri);
-                                        g.add_reply(did_clone, rkey_clone, rkey_parent).await?;
-                                        is_reply = true;
+                        if let Some(r) = &commit.record {
+                            created_at = match chrono::DateTime::parse_from_rfc3339(&r.created_at) {
+                                Ok(t) => {
+                                    if now - t.timestamp_micros()
+                                        > chrono::Duration::hours(24).num_microseconds().unwrap()
+                                    {
+                                        return Ok(Some(time_us));
                                     }
-                                    _ => {}
+                                    t.timestamp_micros()
                                 }
+                                Err(_) => time_us,
+                            };
+                            if let Some(r) = &r.reply {
+                                let parent = match AtUri::parse(&r.parent.uri) {
+                                    Ok(p) => p,
+                                    Err(e) => return Err(IngestError::Schema(e)),
+                                };
+                                let root = match AtUri::parse(&r.root.uri) {
+                                    Ok(p) => p,
+                                    Err(e) => return Err(IngestError::Schema(e)),
+                                };
+                                reply = Some((parent, root));
                             }
-                            _ => {}
+                            content = post_content(r)?;
                         }
 
+                        // Everything after this MATCHes the post, and any of them can set off a
+                        // flush, so the post has to be queued first
+                        let did = deser_evt.did;
                         let res = g
-                            .add_post(deser_evt.did, rkey, &created_at, is_reply, is_image)
+                            .add_post(
+                                did.clone(),
+                                rkey.clone(),
+                                &created_at,
+                                reply.is_some(),
+                                content.media,
+                                content.langs,
+                            )
                             .await?;
-                        match res {
-                            true => {
-                                println!("{drift}ms late")
-                            }
-                            false => {}
+                        if let Some((parent, root)) = reply {
+                            g.add_reply(did.clone(), rkey.clone(), parent, root).await?;
+                        }
+                        if let Some(quoted) = content.quote {
+                            g.add_quote(did.clone(), rkey.clone(), quoted).await?;
+                        }
+                        for mentioned in content.mentions {
+                            g.add_mention(did.clone(), rkey.clone(), mentioned).await?;
+                        }
+                        for tag in content.tags {
+                            g.add_tag(did.clone(), rkey.clone(), tag).await?;
+                        }
+                        for domain in content.domains {
+                            g.add_link(did.clone(), rkey.clone(), domain).await?;
+                        }
+                        if res {
+                            println!("{drift}ms late")
                         }
                     }
 
                     "app.bsky.feed.repost" => {
-                        let rkey_out = get_rkey(&commit);
-
-                        if rkey_out.is_empty() {
-                            panic!("empty rkey");
+                        let subject = subject_uri(commit)?;
+                        // Feed generators & lists get liked too, we only track posts
+                        if !subject.is_post() {
+                            return Ok(Some(time_us));
                         }
-
-                        let res = g
-                            .add_repost(deser_evt.did, rkey_out.to_string(), rkey)
-                            .await?;
-                        match res {
-                            true => {
-                                println!("{drift}ms late")
-                            }
-                            false => {}
+                        let res = g.add_repost(deser_evt.did, subject, rkey).await?;
+                        if res {
+                            println!("{drift}ms late")
                         }
                     }
 
                     "app.bsky.feed.like" => {
-                        let rkey_out = get_rkey(&commit);
-
-                        if rkey_out.is_empty() {
-                            panic!("empty rkey");
+                        let subject = subject_uri(commit)?;
+                        // Feed generators & lists get liked too, we only track posts
+                        if !subject.is_post() {
+                            return Ok(Some(time_us));
                         }
-
-                        let res = g
-                            .add_like(deser_evt.did, rkey_out.to_string(), rkey)
-                            .await?;
-                        match res {
-                            true => {
-                                println!("{drift}ms late")
-                            }
-                            false => {}
+                        let res = g.add_like(deser_evt.did, subject, rkey).await?;
+                        if res {
+                            println!("{drift}ms late")
                         }
                     }
 
                     "app.bsky.graph.follow" => {
                         let mut did_in = String::new();
-                        match &commit.record {
-                            Some(r) => {
-                                did_in = match &r.subject {
-                                    Some(s) => match s {
-                                        Subj::T1(s) => s.to_owned(),
-                                        Subj::T2(_) => return Ok(()),
-                                    },
-                                    None => return Ok(()),
-                                };
-                            }
-                            None => {}
+                        if let Some(r) = &commit.record {
+                            did_in = match &r.subject {
+                                Some(s) => match s {
+                                    Subj::T1(s) => s.to_owned(),
+                                    Subj::T2(_) => return Ok(Some(time_us)),
+                                },
+                                None => return Ok(Some(time_us)),
+                            };
                         }
                         if did_in.is_empty() {
-                            panic!("empty did_in");
+                            return Err(IngestError::Schema(format!(
+                                "{} {rkey} has no record",
+                                commit.collection
+                            )));
                         }
                         let res = g.add_follow(deser_evt.did, did_in, rkey).await?;
-                        match res {
-                            true => {
-                                println!("{drift}ms late")
-                            }
-                            false => {}
+                        if res {
+                            println!("{drift}ms late")
                         }
                     }
 
                     "app.bsky.graph.block" => {
                         let mut did_in = String::new();
-                        match &commit.record {
-                            Some(r) => {
-                                did_in = match &r.subject {
-                                    Some(s) => match s {
-                                        Subj::T1(s) => s.to_owned(),
-                                        Subj::T2(_) => return Ok(()),
-                                    },
-                                    None => return Ok(()),
-                                };
-                            }
-                            None => {}
+                        if let Some(r) = &commit.record {
+                            did_in = match &r.subject {
+                                Some(s) => match s {
+                                    Subj::T1(s) => s.to_owned(),
+                                    Subj::T2(_) => return Ok(Some(time_us)),
+                                },
+                                None => return Ok(Some(time_us)),
+                            };
                         }
                         if did_in.is_empty() {
-                            panic!("empty did_in");
+                            return Err(IngestError::Schema(format!(
+                                "{} {rkey} has no record",
+                                commit.collection
+                            )));
+                        }
+                        g.add_block(did_in, deser_evt.did, rkey).await?;
+                    }
+                    "app.bsky.graph.list" => {
+                        let record = match &commit.record {
+                            Some(r) => r,
+                            None => {
+                                return Err(IngestError::Schema(format!(
+                                    "{} {rkey} has no record",
+                                    commit.collection
+                                )))
+                            }
+                        };
+                        g.add_list(
+                            deser_evt.did,
+                            rkey,
+                            record.name.clone().unwrap_or_default(),
+                            list_purpose(record),
+                            record.description.clone().unwrap_or_default(),
+                        )
+                        .await?;
+                    }
+                    "app.bsky.graph.listitem" => {
+                        let (list, subject) = match &commit.record {
+                            Some(Record {
+                                list: Some(list),
+                                subject: Some(Subj::T1(subject)),
+                                ..
+                            }) => (list, subject),
+                            _ => {
+                                return Err(IngestError::Schema(format!(
+                                    "{} {rkey} has no list or subject",
+                                    commit.collection
+                                )))
+                            }
+                        };
+                        let list = AtUri::parse(list).map_err(IngestError::Schema)?;
+                        // Only the list's owner can add to it
+                        if list.authority != deser_evt.did {
+                            return Ok(Some(time_us));
                         }
-                        g.add_block(deser_evt.did, did_in, rkey).await?;
+                        g.add_listitem(deser_evt.did, rkey, list, subject.to_owned())
+                            .await?;
+                    }
+                    // Profiles only matter for users we have, so creates are the same as updates
+                    "app.bsky.actor.profile" => {
+                        if let Some(r) = &commit.record {
+                            if let Some(pack) = &r.joined_via_starter_pack {
+                                let pack = AtUri::parse(&pack.uri).map_err(IngestError::Schema)?;
+                                g.add_joined_via(deser_evt.did.clone(), pack).await?;
+                            }
+                            g.update_profile(
+                                deser_evt.did,
+                                r.display_name.clone().unwrap_or_default(),
+                                r.description.clone().unwrap_or_default(),
+                            )
+                            .await?;
+                        }
+                    }
+                    "app.bsky.feed.threadgate" | "app.bsky.feed.postgate" => {
+                        handle_gate(commit, deser_evt.did, g).await?;
+                    }
+                    "app.bsky.graph.starterpack" => {
+                        let (record, list) = starter_pack_list(commit)?;
+                        g.add_starterpack(
+                            deser_evt.did,
+                            rkey,
+                            record.name.clone().unwrap_or_default(),
+                            list,
+                        )
+                        .await?;
                     }
                     _ => {
                         //println!("{:?}", mm);
@@ -213,6 +302,8 @@ pub async fn handle_event(
             } else if commit.operation == "delete" {
                 match commit.collection.as_str() {
                     "app.bsky.feed.post" => {
+                        // We dont know if it was a reply, but removing an edge that isnt there is free
+                        g.rm_reply(deser_evt.did.clone(), rkey.clone()).await?;
                         g.rm_post(deser_evt.did, rkey).await?;
                     }
                     "app.bsky.feed.repost" => {
@@ -228,111 +319,492 @@ pub async fn handle_event(
                     "app.bsky.graph.block" => {
                         g.rm_block(deser_evt.did, rkey).await?;
                     }
+                    "app.bsky.graph.list" => {
+                        g.rm_list(deser_evt.did, rkey).await?;
+                    }
+                    "app.bsky.graph.listitem" => {
+                        g.rm_listitem(deser_evt.did, rkey).await?;
+                    }
+                    "app.bsky.graph.starterpack" => {
+                        g.rm_starterpack(deser_evt.did, rkey).await?;
+                    }
+                    // Gates share their post's rkey
+                    "app.bsky.feed.threadgate" => {
+                        g.rm_threadgate(deser_evt.did, rkey).await?;
+                    }
+                    "app.bsky.feed.postgate" => {
+                        g.rm_postgate(deser_evt.did, rkey).await?;
+                    }
                     _ => {
                         //println!("{:?}", deser_evt);
                     }
                 }
-            } else {
-                // TODO - Handle Updates (lists, starterpacks?)
-                //println!("{:?}", deser_evt.commit.unwrap().collection);
+            } else if commit.operation == "update" {
+                // Same keys as before, so overwrite what we have rather than delete & recreate.
+                // Nothing to do if we never had it
+                let record = match &commit.record {
+                    Some(r) => r,
+                    None => {
+                        return Err(IngestError::Schema(format!(
+                            "{} {rkey} update has no record",
+                            commit.collection
+                        )))
+                    }
+                };
+                match commit.collection.as_str() {
+                    "app.bsky.feed.threadgate" | "app.bsky.feed.postgate" => {
+                        handle_gate(commit, deser_evt.did, g).await?;
+                    }
+                    "app.bsky.feed.post" => {
+                        g.update_post(deser_evt.did, rkey, post_content(record)?)
+                            .await?;
+                    }
+                    "app.bsky.actor.profile" => {
+                        g.update_profile(
+                            deser_evt.did,
+                            record.display_name.clone().unwrap_or_default(),
+                            record.description.clone().unwrap_or_default(),
+                        )
+                        .await?;
+                    }
+                    // Packs can be pointed at a different list, which add_starterpack deals with
+                    "app.bsky.graph.starterpack" => {
+                        let (record, list) = starter_pack_list(commit)?;
+                        g.add_starterpack(
+                            deser_evt.did,
+                            rkey,
+                            record.name.clone().unwrap_or_default(),
+                            list,
+                        )
+                        .await?;
+                    }
+                    "app.bsky.graph.list" => {
+                        g.update_list(
+                            deser_evt.did,
+                            rkey,
+                            record.name.clone().unwrap_or_default(),
+                            list_purpose(record),
+                            record.description.clone().unwrap_or_default(),
+                        )
+                        .await?;
+                    }
+                    _ => {}
+                }
             }
 
-            return Ok(());
+            Ok(Some(time_us))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Identity & account events, everything that isnt a commit
+async fn handle_repo_event(evt: &BskyEvent, g: &mut GraphModel) -> Result<(), IngestError> {
+    match (evt.kind.as_str(), &evt.identity, &evt.account) {
+        ("identity", Some(identity), _) => {
+            if let Some(handle) = &identity.handle {
+                g.set_handle(evt.did.clone(), handle.to_owned()).await?;
+            }
         }
-        Err(err) => {
-            return Err(err.into());
+        ("account", _, Some(account)) => {
+            let status = match (account.active, account.status.as_deref()) {
+                (true, _) => AccountState::Active,
+                // Gone for good, nothing to come back to
+                (false, Some("deleted")) => AccountState::Deleted,
+                // Deactivated, suspended & takendown accounts can all come back
+                (false, _) => AccountState::Hidden,
+            };
+            g.set_account_state(evt.did.clone(), status).await?;
         }
+        _ => {}
     }
+    Ok(())
 }
 
-fn parse_rkey(uri: &str) -> String {
-    // the rkey are the last 13 characters
-    uri.chars()
-        .rev()
-        .take(13)
-        .collect::<Vec<_>>()
-        .iter()
-        .rev()
-        .collect()
+// Threadgates & postgates, both overwrite whatever the post had before so creates & updates are the same.
+// Only the post's author can gate it
+async fn handle_gate(commit: &Commit, did: String, g: &mut GraphModel) -> Result<(), IngestError> {
+    let record = match &commit.record {
+        Some(r) => r,
+        None => {
+            return Err(IngestError::Schema(format!(
+                "{} {} has no record",
+                commit.collection, commit.rkey
+            )))
+        }
+    };
+    let post = match &record.post {
+        Some(p) => AtUri::parse(p).map_err(IngestError::Schema)?,
+        None => {
+            return Err(IngestError::Schema(format!(
+                "{} {} has no post",
+                commit.collection, commit.rkey
+            )))
+        }
+    };
+    if post.authority != did || !post.is_post() {
+        return Ok(());
+    }
+
+    if commit.collection == "app.bsky.feed.threadgate" {
+        // No allow at all means anyone can reply, an empty one means nobody can
+        g.add_threadgate(did, post.rkey, record.allow.is_some())
+            .await?;
+    } else {
+        let quotes_disabled = record
+            .embedding_rules
+            .iter()
+            .flatten()
+            .any(|r| r.type_field == "app.bsky.feed.postgate#disableRule");
+        let detached = record
+            .detached_embedding_uris
+            .iter()
+            .flatten()
+            .filter_map(|u| AtUri::parse(u).ok())
+            .collect();
+        g.add_postgate(did, post.rkey, quotes_disabled, detached)
+            .await?;
+    }
+    Ok(())
 }
 
-fn get_rkey(commit: &Commit) -> String {
-    let rkey_out;
+// Starter packs are a list with a name on, the list is where the members are
+fn starter_pack_list(commit: &Commit) -> Result<(&Record, AtUri), IngestError> {
     match &commit.record {
-        Some(r) => {
-            rkey_out = match &r.subject {
-                Some(s) => match s {
-                    Subj::T1(_) => "".to_owned(),
-                    Subj::T2(subject) => parse_rkey(&subject.uri),
-                },
-                None => "".to_owned(),
-            };
+        Some(r) => match &r.list {
+            Some(list) => Ok((r, AtUri::parse(list).map_err(IngestError::Schema)?)),
+            None => Err(IngestError::Schema(format!(
+                "{} {} has no list",
+                commit.collection, commit.rkey
+            ))),
+        },
+        None => Err(IngestError::Schema(format!(
+            "{} {} has no record",
+            commit.collection, commit.rkey
+        ))),
+    }
+}
+
+// app.bsky.graph.defs#curatelist -> curate
+fn list_purpose(record: &Record) -> String {
+    let purpose = record.purpose.as_deref().unwrap_or_default();
+    let purpose = purpose.rsplit('#').next().unwrap_or_default();
+    purpose.strip_suffix("list").unwrap_or(purpose).to_owned()
+}
+
+// Everything about a post that can change when it is edited
+fn post_content(record: &Record) -> Result<PostContent, IngestError> {
+    let mut content = PostContent {
+        media: post_media(record.embed.as_ref()),
+        // lang is from before posts could have more than one
+        langs: match (&record.langs, &record.lang) {
+            (Some(l), _) => lang::normalize_all(l.iter().map(|l| l.as_str())),
+            (None, Some(l)) => lang::normalize_all([l.as_str()]),
+            (None, None) => Vec::new(),
+        },
+        quote: quoted_uri(record)?,
+        ..Default::default()
+    };
+
+    let features = record.facets.iter().flatten().flat_map(|f| &f.features);
+    for feature in features {
+        match feature {
+            Feature::Mention { did } => {
+                if !content.mentions.contains(did) {
+                    content.mentions.push(did.to_owned());
+                }
+            }
+            Feature::Tag { tag } => {
+                if let Some(tag) = normalize_tag(tag) {
+                    if !content.tags.contains(&tag) {
+                        content.tags.push(tag);
+                    }
+                }
+            }
+            Feature::Link { uri } => {
+                if let Some(domain) = link_domain(uri) {
+                    if !content.domains.contains(&domain) {
+                        content.domains.push(domain);
+                    }
+                }
+            }
+            Feature::Unknown => {}
         }
-        None => rkey_out = "".to_owned(),
     }
-    rkey_out
+    Ok(content)
+}
+
+// The post a quote post embeds, if it is one. Records can embed feeds, lists & starter packs too
+fn quoted_uri(record: &Record) -> Result<Option<AtUri>, IngestError> {
+    let subject = match &record.embed {
+        Some(Embed::Record { record }) => record,
+        Some(Embed::RecordWithMedia { record, .. }) => &record.record,
+        _ => return Ok(None),
+    };
+    match AtUri::parse(&subject.uri) {
+        Ok(uri) if uri.is_post() => Ok(Some(uri)),
+        Ok(_) => Ok(None),
+        Err(e) => Err(IngestError::Schema(e)),
+    }
+}
+
+fn post_media(embed: Option<&Embed>) -> PostMedia {
+    match embed {
+        Some(Embed::Images { images }) => PostMedia {
+            kind: "images",
+            image_count: images.len(),
+            has_alt: !images.is_empty() && images.iter().all(|i| !i.alt.trim().is_empty()),
+            link_domain: None,
+        },
+        Some(Embed::Video(v)) => PostMedia {
+            kind: "video",
+            has_alt: v.alt.as_ref().is_some_and(|a| !a.trim().is_empty()),
+            ..Default::default()
+        },
+        Some(Embed::External { external }) => PostMedia {
+            kind: "external",
+            link_domain: link_domain(&external.uri),
+            ..Default::default()
+        },
+        // The quote itself is a QUOTES edge, only the media counts here
+        Some(Embed::RecordWithMedia { media, .. }) => post_media(Some(media)),
+        Some(Embed::Record { .. }) | Some(Embed::Unknown) | None => PostMedia::default(),
+    }
+}
+
+// www.example.com and example.com are the same site as far as anyone reading is concerned
+fn link_domain(uri: &str) -> Option<String> {
+    let url = reqwest::Url::parse(uri).ok()?;
+    let host = url.host_str()?.to_lowercase();
+    Some(host.strip_prefix("www.").unwrap_or(&host).to_owned())
+}
+
+// #Rust, #rust and rust are all the same tag.
+// Tags cant have spaces or commas in them anyway, and commas are what UPDATE_POST splits on
+fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim().trim_start_matches(['#', '＃']).to_lowercase();
+    if tag.is_empty() || tag.contains(|c: char| c == ',' || c.is_whitespace()) {
+        None
+    } else {
+        Some(tag)
+    }
 }
 
-pub async fn get_followers(
-    did: String,
-    client: &reqwest::Client,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut url = format!(
-        "https://public.api.bsky.app/xrpc/app.bsky.graph.getFollowers?limit=100&actor={}",
-        did
-    );
+// The post (or whatever else) a like or repost points at
+fn subject_uri(commit: &Commit) -> Result<AtUri, IngestError> {
+    let uri = match &commit.record {
+        Some(r) => match &r.subject {
+            Some(Subj::T2(subject)) => subject.uri.as_str(),
+            _ => "",
+        },
+        None => "",
+    };
+    if uri.is_empty() {
+        return Err(IngestError::Schema(format!(
+            "{} {} has no subject uri",
+            commit.collection, commit.rkey
+        )));
+    }
+    AtUri::parse(uri).map_err(IngestError::Schema)
+}
+
+pub async fn get_followers(did: &str, client: &XrpcClient) -> Result<Vec<String>, ClientError> {
     let mut followers: Vec<String> = Vec::new();
-    let mut req = client.get(&url).build()?;
-    let mut resp: FollowersResp = client.execute(req).await?.json().await?;
+    let mut cursor: Option<String> = None;
 
     loop {
+        let mut params = vec![("actor", did), ("limit", "100")];
+        if let Some(c) = &cursor {
+            params.push(("cursor", c.as_str()));
+        }
+        let mut resp: FollowersResp = client.get("app.bsky.graph.getFollowers", &params).await?;
+
         for f in &mut resp.followers {
             let str = mem::take(&mut f.did); // yoink the string, not gonna need it anymore in the vec anyway
             followers.push(str);
         }
-        match &resp.cursor {
-            Some(c) => {
-                url = url + format!("&cursor={}", c).as_str();
-                req = client.get(&url).build()?;
-                resp = client.execute(req).await?.json().await?;
-            }
-            None => {
-                break;
-            }
+        // An empty page or a repeated cursor means we're at the end, whatever the cursor says
+        if resp.followers.is_empty() || resp.cursor == cursor {
+            break;
+        }
+        match resp.cursor {
+            Some(c) => cursor = Some(c),
+            None => break,
         }
     }
 
     Ok(followers)
 }
 
-pub async fn get_follows(
-    did: String,
-    client: &reqwest::Client,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut url = format!(
-        "https://public.api.bsky.app/xrpc/app.bsky.graph.getFollows?limit=100&actor={}",
-        did
-    );
+pub async fn get_follows(did: &str, client: &XrpcClient) -> Result<Vec<String>, ClientError> {
     let mut follows: Vec<String> = Vec::new();
-    let mut req = client.get(&url).build()?;
-    let mut resp: FollowsResp = client.execute(req).await?.json().await?;
+    let mut cursor: Option<String> = None;
 
     loop {
+        let mut params = vec![("actor", did), ("limit", "100")];
+        if let Some(c) = &cursor {
+            params.push(("cursor", c.as_str()));
+        }
+        let mut resp: FollowsResp = client.get("app.bsky.graph.getFollows", &params).await?;
+
         for f in &mut resp.follows {
             let str = mem::take(&mut f.did); // yoink the string, not gonna need it anymore in the vec anyway
             follows.push(str);
         }
-        match &resp.cursor {
-            Some(c) => {
-                url = url + format!("&cursor={}", c).as_str();
-                req = client.get(&url).build()?;
-                resp = client.execute(req).await?.json().await?;
-            }
-            None => {
-                break;
-            }
+        if resp.follows.is_empty() || resp.cursor == cursor {
+            break;
+        }
+        match resp.cursor {
+            Some(c) => cursor = Some(c),
+            None => break,
         }
     }
 
     Ok(follows)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{QueueConfig, Written};
+    use serde_json::json;
+    use std::time::Duration;
+
+    const DID: &str = "did:plc:author";
+
+    fn post_event(rkey: &str, record: serde_json::Value) -> Message {
+        event("create", "app.bsky.feed.post", rkey, record)
+    }
+
+    fn event(operation: &str, collection: &str, rkey: &str, record: serde_json::Value) -> Message {
+        let now = Utc::now();
+        let mut record = record;
+        record["$type"] = json!(collection);
+        record["createdAt"] = json!(now.to_rfc3339());
+        let evt = json!({
+            "did": DID,
+            "time_us": now.timestamp_micros(),
+            "kind": "commit",
+            "commit": {
+                "rev": "3l",
+                "operation": operation,
+                "collection": collection,
+                "rkey": rkey,
+                "record": record,
+            },
+        });
+        Message::Text(evt.to_string())
+    }
+
+    fn reply_to() -> serde_json::Value {
+        let parent = json!({"cid": "bafy", "uri": "at://did:plc:op/app.bsky.feed.post/root"});
+        json!({"parent": parent, "root": parent})
+    }
+
+    // A queue of 1 means every push after the first sets off a size flush
+    async fn run(events: Vec<Message>) -> Written {
+        let mut g = GraphModel::offline(QueueConfig {
+            add_limit: 1,
+            rm_limit: 1,
+            max_age: Duration::from_secs(60),
+        })
+        .await;
+        for evt in events {
+            handle_event(Ok(evt), &mut g, false, true).await.unwrap();
+        }
+        g.written()
+    }
+
+    // Every row of `name` that was written has to have had its post written before it
+    fn assert_post_first(written: &Written, name: &str) {
+        let mut posts = HashSet::new();
+        let mut seen = 0;
+        for (query, rows) in written {
+            for row in rows {
+                let key = (row["did"].clone(), row["rkey"].clone());
+                if query == "post" {
+                    posts.insert(key);
+                } else if query == name {
+                    assert!(
+                        posts.contains(&key),
+                        "{name} {key:?} went out before its post"
+                    );
+                    seen += 1;
+                }
+            }
+        }
+        assert!(seen > 0, "no {name} rows were written");
+    }
+
+    #[tokio::test]
+    async fn reply_flush_writes_its_post_first() {
+        let written = run(vec![
+            post_event("a", json!({"text": "one", "reply": reply_to()})),
+            post_event("b", json!({"text": "two", "reply": reply_to()})),
+        ])
+        .await;
+        assert_post_first(&written, "reply");
+    }
+
+    #[tokio::test]
+    async fn quote_flush_writes_its_post_first() {
+        let embed = json!({
+            "$type": "app.bsky.embed.record",
+            "record": {"cid": "bafy", "uri": "at://did:plc:op/app.bsky.feed.post/quoted"},
+        });
+        let written = run(vec![
+            post_event("a", json!({"text": "one", "embed": embed})),
+            post_event("b", json!({"text": "two", "embed": embed})),
+        ])
+        .await;
+        assert_post_first(&written, "quote");
+    }
+
+    #[tokio::test]
+    async fn facet_flushes_write_their_post_first() {
+        let facets = json!([{"features": [
+            {"$type": "app.bsky.richtext.facet#mention", "did": "did:plc:friend"},
+            {"$type": "app.bsky.richtext.facet#tag", "tag": "rust"},
+            {"$type": "app.bsky.richtext.facet#link", "uri": "https://example.com/a"},
+        ]}]);
+        let written = run(vec![
+            post_event("a", json!({"text": "one", "facets": facets})),
+            post_event("b", json!({"text": "two", "facets": facets})),
+        ])
+        .await;
+        for name in ["mention", "tag", "link"] {
+            assert_post_first(&written, name);
+        }
+    }
+
+    // Gates & edits come in as their own events, but the post can still be sitting in its queue
+    #[tokio::test]
+    async fn gate_and_edit_flushes_write_their_post_first() {
+        let post = json!({"post": format!("at://{DID}/app.bsky.feed.post/a")});
+        let mut threadgate = post.clone();
+        threadgate["allow"] = json!([]);
+        let mut events = vec![post_event("a", json!({"text": "one"}))];
+        // Second round of each fills its queue & sets off the flush
+        for op in ["create", "update"] {
+            events.push(event(
+                op,
+                "app.bsky.feed.threadgate",
+                "a",
+                threadgate.clone(),
+            ));
+            events.push(event(op, "app.bsky.feed.postgate", "a", post.clone()));
+        }
+        for text in ["edited", "edited again"] {
+            events.push(event(
+                "update",
+                "app.bsky.feed.post",
+                "a",
+                json!({"text": text}),
+            ));
+        }
+        let written = run(events).await;
+        for name in ["threadgate", "postgate", "post_update"] {
+            assert_post_first(&written, name);
+        }
+    }
+}
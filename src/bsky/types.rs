@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
@@ -35,6 +33,25 @@ pub struct BskyEvent {
     #[serde(rename = "type")]
     pub type_field: Option<String>,
     pub commit: Option<Commit>,
+    pub identity: Option<Identity>,
+    pub account: Option<AccountStatus>,
+}
+
+// kind: identity, the handle (or DID document) changed
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+    pub did: String,
+    pub handle: Option<String>,
+}
+
+// kind: account, status is only set when active is false
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountStatus {
+    pub did: String,
+    pub active: bool,
+    pub status: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -53,6 +70,8 @@ pub struct Commit {
 pub struct Record {
     #[serde(rename = "$type")]
     pub type_field: String,
+    // Profiles dont have to have one
+    #[serde(default)]
     pub created_at: String,
     pub subject: Option<Subj>,
     pub lang: Option<String>,
@@ -60,24 +79,118 @@ pub struct Record {
     pub text: Option<String>,
     pub reply: Option<Reply>,
     pub embed: Option<Embed>,
-    pub images: Option<Vec<Image>>,
+    pub facets: Option<Vec<Facet>>,
+    // Profiles & lists
+    pub display_name: Option<String>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub purpose: Option<String>,
+    // Listitems & starter packs
+    pub list: Option<String>,
+    // Profiles of people who signed up from a starter pack
+    pub joined_via_starter_pack: Option<Subject>,
+    // Threadgates & postgates, post is the post they apply to
+    pub post: Option<String>,
+    pub allow: Option<Vec<GateRule>>,
+    pub embedding_rules: Option<Vec<GateRule>>,
+    pub detached_embedding_uris: Option<Vec<String>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GateRule {
+    #[serde(rename = "$type")]
+    pub type_field: String,
+}
+
+// A span of the post text, with whatever it mentions/links/tags
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Facet {
+    pub features: Vec<Feature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "$type")]
+pub enum Feature {
+    #[serde(rename = "app.bsky.richtext.facet#mention")]
+    Mention { did: String },
+    #[serde(rename = "app.bsky.richtext.facet#link")]
+    Link { uri: String },
+    #[serde(rename = "app.bsky.richtext.facet#tag")]
+    Tag { tag: String },
+    #[serde(other)]
+    Unknown,
+}
+
+// Unknown or future embed types come through as Unknown rather than failing the whole post
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "$type")]
+pub enum Embed {
+    #[serde(rename = "app.bsky.embed.images")]
+    Images { images: Vec<Image> },
+    #[serde(rename = "app.bsky.embed.video")]
+    Video(Video),
+    #[serde(rename = "app.bsky.embed.external")]
+    External { external: External },
+    #[serde(rename = "app.bsky.embed.record")]
+    Record { record: Subject },
+    #[serde(rename = "app.bsky.embed.recordWithMedia")]
+    RecordWithMedia {
+        record: EmbedRecord,
+        media: Box<Embed>,
+    },
+    #[serde(other)]
+    Unknown,
 }
 
+// The app.bsky.embed.record inside a recordWithMedia
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+pub struct EmbedRecord {
+    pub record: Subject,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Image {
     pub alt: String,
-    pub aspect_ratio: HashMap<String, String>,
-    pub image: ImageInternal,
+    pub aspect_ratio: Option<AspectRatio>,
+    pub image: Blob,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Video {
+    pub video: Blob,
+    pub alt: Option<String>,
+    pub aspect_ratio: Option<AspectRatio>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct External {
+    pub uri: String,
+    pub title: String,
+    pub description: String,
+    pub thumb: Option<Blob>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct ImageInternal {
+pub struct AspectRatio {
+    pub width: u64,
+    pub height: u64,
+}
+
+// Older records have legacy blobs without the $type & ref, so everything is optional
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Blob {
     #[serde(rename = "$type")]
     pub type_field: String,
     #[serde(rename = "ref")]
-    pub reff: Ref,
+    pub reff: Option<Ref>,
     pub mime_type: String,
     pub size: u64,
 }
@@ -89,15 +202,6 @@ pub struct Ref {
     pub link: String,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Embed {
-    #[serde(rename = "$type")]
-    pub type_field: String,
-    pub uri: Option<String>,
-    pub embedded: Option<Embd>,
-}
-
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Reply {
@@ -125,10 +229,3 @@ pub enum Subj {
     T1(String),
     T2(Subject),
 }
-
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum Embd {
-    T1(String),
-    T2(Subject),
-}
@@ -0,0 +1,309 @@
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use std::{
+    env, fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+const DEFAULT_BASE_URL: &str = "https://public.api.bsky.app";
+// The public AppView allows 3000 requests per 5 minutes per IP, stay comfortably under that
+const DEFAULT_RATE_PER_SEC: f64 = 8.0;
+const DEFAULT_BURST: f64 = 20.0;
+const MAX_RETRIES: u32 = 5;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    // Non-retryable status, with whatever body came back
+    Status(StatusCode, String),
+    RetriesExhausted(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "http error: {e}"),
+            ClientError::Status(s, body) => write!(f, "{s}: {body}"),
+            ClientError::RetriesExhausted(e) => write!(f, "gave up after {MAX_RETRIES} tries: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    // How long until a token is free, taking it if one is free now
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate)
+            .min(self.capacity);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+// Rate limited, retrying client for unauthenticated XRPC queries against an AppView
+pub struct XrpcClient {
+    base_url: String,
+    client: reqwest::Client,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl XrpcClient {
+    pub fn new(base_url: &str, rate_per_sec: f64, burst: f64) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            client: reqwest::Client::new(),
+            bucket: Mutex::new(TokenBucket {
+                tokens: burst,
+                capacity: burst,
+                rate: rate_per_sec,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    // APPVIEW_URL & APPVIEW_RATE_LIMIT (requests/s) override the public AppView defaults
+    pub fn from_env() -> Self {
+        let base_url = env::var("APPVIEW_URL").unwrap_or(DEFAULT_BASE_URL.into());
+        let rate = env::var("APPVIEW_RATE_LIMIT")
+            .ok()
+            .and_then(|r| r.parse::<f64>().ok())
+            .filter(|r| *r > 0.0)
+            .unwrap_or(DEFAULT_RATE_PER_SEC);
+        Self::new(&base_url, rate, DEFAULT_BURST.max(rate))
+    }
+
+    async fn acquire(&self) {
+        loop {
+            let wait = self.bucket.lock().await.take();
+            match wait {
+                Some(w) => tokio::time::sleep(w).await,
+                None => return,
+            }
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        nsid: &str,
+        params: &[(&str, &str)],
+    ) -> Result<T, ClientError> {
+        let url = format!("{}/xrpc/{}", self.base_url, nsid);
+        let mut last_err = String::new();
+
+        for attempt in 0..MAX_RETRIES {
+            self.acquire().await;
+            let backoff = (BASE_BACKOFF * 2u32.pow(attempt)).min(MAX_BACKOFF);
+
+            let resp = match self.client.get(&url).query(params).send().await {
+                Ok(r) => r,
+                Err(e) => {
+                    last_err = e.to_string();
+                    tokio::time::sleep(backoff).await;
+                    continue;
+                }
+            };
+
+            let status = resp.status();
+            if status.is_success() {
+                return resp.json().await.map_err(ClientError::Http);
+            }
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                // RateLimit-Reset is when the window resets, in unix seconds. One thats already
+                // here (clock skew, or it just rolled over) still gets the backoff, otherwise
+                // we'd hammer straight back in
+                let wait = resp
+                    .headers()
+                    .get("ratelimit-reset")
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.parse::<u64>().ok())
+                    .and_then(|reset| {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+                        Some(Duration::from_secs(reset.saturating_sub(now.as_secs())))
+                    })
+                    .map_or(backoff, |reset| reset.max(backoff))
+                    .min(MAX_BACKOFF);
+                println!(
+                    "Rate limited by {}, waiting {}s",
+                    self.base_url,
+                    wait.as_secs()
+                );
+                last_err = status.to_string();
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+
+            if status.is_server_error() {
+                last_err = status.to_string();
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+
+            let body = resp.text().await.unwrap_or_default();
+            return Err(ClientError::Status(status, body));
+        }
+
+        Err(ClientError::RetriesExhausted(last_err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::{RawQuery, State},
+        http::HeaderMap,
+        response::{IntoResponse, Response},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use std::{collections::VecDeque, sync::Arc};
+    use tokio::{net::TcpListener, time};
+
+    // Hands out canned responses in order, 500s once they run out, and keeps every query string
+    #[derive(Default)]
+    struct Mock {
+        responses: std::sync::Mutex<VecDeque<Response>>,
+        queries: std::sync::Mutex<Vec<String>>,
+    }
+
+    async fn respond(State(mock): State<Arc<Mock>>, RawQuery(q): RawQuery) -> Response {
+        mock.queries.lock().unwrap().push(q.unwrap_or_default());
+        let next = mock.responses.lock().unwrap().pop_front();
+        next.unwrap_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+    }
+
+    async fn serve(responses: Vec<Response>) -> (XrpcClient, Arc<Mock>) {
+        let mock = Arc::new(Mock {
+            responses: std::sync::Mutex::new(responses.into()),
+            ..Default::default()
+        });
+        let app = Router::new().fallback(respond).with_state(mock.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (
+            XrpcClient::new(&format!("http://{addr}/"), 1000.0, 1000.0),
+            mock,
+        )
+    }
+
+    fn ok(body: Value) -> Response {
+        axum::Json(body).into_response()
+    }
+
+    fn rate_limited(reset: u64) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert("ratelimit-reset", reset.into());
+        (StatusCode::TOO_MANY_REQUESTS, headers).into_response()
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn follows(dids: &[&str], cursor: Option<&str>) -> Response {
+        let follows: Vec<_> = dids
+            .iter()
+            .map(|d| json!({"did": d, "handle": d}))
+            .collect();
+        ok(json!({"follows": follows, "cursor": cursor}))
+    }
+
+    #[tokio::test]
+    async fn follows_page_through_cursors() {
+        let (client, mock) = serve(vec![
+            follows(&["did:plc:a", "did:plc:b"], Some("c1")),
+            follows(&["did:plc:c"], Some("c2")),
+            // The AppView repeats the cursor on the last page sometimes
+            follows(&["did:plc:d"], Some("c2")),
+        ])
+        .await;
+        let got = crate::bsky::get_follows("did:plc:me", &client)
+            .await
+            .unwrap();
+        assert_eq!(got, ["did:plc:a", "did:plc:b", "did:plc:c", "did:plc:d"]);
+
+        let queries = mock.queries.lock().unwrap();
+        assert_eq!(queries.len(), 3);
+        assert!(!queries[0].contains("cursor"));
+        assert!(queries[1].contains("cursor=c1"));
+        assert!(queries[2].contains("cursor=c2"));
+    }
+
+    // Timers are paused, so time only moves as far as the client sleeps
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_waits_for_reset() {
+        let (client, _) = serve(vec![rate_limited(unix_now() + 10), ok(json!({}))]).await;
+        let start = time::Instant::now();
+        client.get::<Value>("x.y.z", &[]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(9));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_reset_in_the_past_still_backs_off() {
+        let (client, _) = serve(vec![rate_limited(unix_now() - 5), ok(json!({}))]).await;
+        let start = time::Instant::now();
+        client.get::<Value>("x.y.z", &[]).await.unwrap();
+        assert!(start.elapsed() >= BASE_BACKOFF);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn server_errors_back_off_exponentially() {
+        let (client, mock) = serve(vec![
+            StatusCode::BAD_GATEWAY.into_response(),
+            StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            ok(json!({"n": 1})),
+        ])
+        .await;
+        let start = time::Instant::now();
+        let got: Value = client.get("x.y.z", &[]).await.unwrap();
+        assert_eq!(got["n"], 1);
+        assert!(start.elapsed() >= BASE_BACKOFF * 3);
+        assert_eq!(mock.queries.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_retries() {
+        let (client, mock) = serve(vec![]).await;
+        match client.get::<Value>("x.y.z", &[]).await {
+            Err(ClientError::RetriesExhausted(e)) => assert!(e.contains("500")),
+            other => panic!("expected RetriesExhausted, got {other:?}"),
+        }
+        assert_eq!(mock.queries.lock().unwrap().len(), MAX_RETRIES as usize);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (client, mock) = serve(vec![(StatusCode::BAD_REQUEST, "nope").into_response()]).await;
+        match client.get::<Value>("x.y.z", &[]).await {
+            Err(ClientError::Status(s, body)) => {
+                assert_eq!(s, StatusCode::BAD_REQUEST);
+                assert_eq!(body, "nope");
+            }
+            other => panic!("expected Status, got {other:?}"),
+        }
+        assert_eq!(mock.queries.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::str::FromStr;
+
+pub const POST_COLLECTION: &str = "app.bsky.feed.post";
+pub const REPOST_COLLECTION: &str = "app.bsky.feed.repost";
+
+// at://<authority>/<collection>/<rkey>, the authority being a DID (or a handle, which we never get
+// from strong refs). Records are only unique per repo, so the rkey on its own doesnt identify anything
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AtUri {
+    pub authority: String,
+    pub collection: String,
+    pub rkey: String,
+}
+
+impl AtUri {
+    pub fn new(authority: &str, collection: &str, rkey: &str) -> Self {
+        Self {
+            authority: authority.to_owned(),
+            collection: collection.to_owned(),
+            rkey: rkey.to_owned(),
+        }
+    }
+
+    pub fn parse(uri: &str) -> Result<Self, String> {
+        let rest = match uri.strip_prefix("at://") {
+            Some(r) => r,
+            None => return Err(format!("not an at uri: {uri}")),
+        };
+        // Query & fragment arent part of the record's identity
+        let rest = rest.split(['?', '#']).next().unwrap_or_default();
+
+        let mut parts = rest.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(authority), Some(collection), Some(rkey), None)
+                if !authority.is_empty() && !collection.is_empty() && !rkey.is_empty() =>
+            {
+                Ok(Self::new(authority, collection, rkey))
+            }
+            _ => Err(format!("not a record uri: {uri}")),
+        }
+    }
+
+    pub fn is_post(&self) -> bool {
+        self.collection == POST_COLLECTION
+    }
+}
+
+impl FromStr for AtUri {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for AtUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "at://{}/{}/{}",
+            self.authority, self.collection, self.rkey
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_did_authority() {
+        let uri = AtUri::parse("at://did:plc:abc123/app.bsky.feed.post/3kabc").unwrap();
+        assert_eq!(uri, AtUri::new("did:plc:abc123", POST_COLLECTION, "3kabc"));
+        assert!(uri.is_post());
+        assert_eq!(
+            uri.to_string(),
+            "at://did:plc:abc123/app.bsky.feed.post/3kabc"
+        );
+    }
+
+    #[test]
+    fn parses_handle_authority() {
+        let uri: AtUri = "at://alice.bsky.social/app.bsky.graph.list/3kxyz"
+            .parse()
+            .unwrap();
+        assert_eq!(uri.authority, "alice.bsky.social");
+        assert_eq!(uri.collection, "app.bsky.graph.list");
+        assert!(!uri.is_post());
+    }
+
+    #[test]
+    fn drops_query_and_fragment() {
+        let uri = AtUri::parse("at://did:plc:abc/app.bsky.feed.post/3kabc?x=1#frag").unwrap();
+        assert_eq!(uri.rkey, "3kabc");
+    }
+
+    #[test]
+    fn rejects_missing_parts() {
+        for uri in [
+            "at://did:plc:abc",
+            "at://did:plc:abc/",
+            "at://did:plc:abc/app.bsky.feed.post",
+            "at://did:plc:abc/app.bsky.feed.post/",
+            "at:///app.bsky.feed.post/3kabc",
+            "at://did:plc:abc//3kabc",
+            "at://did:plc:abc/app.bsky.feed.post/3kabc/extra",
+        ] {
+            assert!(AtUri::parse(uri).is_err(), "{uri}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        for uri in [
+            "https://bsky.app/profile/did:plc:abc/post/3kabc",
+            "did:plc:abc/app.bsky.feed.post/3kabc",
+            "AT://did:plc:abc/app.bsky.feed.post/3kabc",
+            "",
+        ] {
+            assert!(AtUri::parse(uri).is_err(), "{uri}");
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Posts & clients tag languages with BCP-47 tags, but en-GB readers can read en-US posts,
+// so everything is compared on the primary subtag alone
+pub fn normalize(tag: &str) -> Option<String> {
+    let primary = tag.trim().split(['-', '_']).next().unwrap_or_default();
+    if primary.is_empty() || primary == "*" || !primary.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    Some(primary.to_ascii_lowercase())
+}
+
+// Normalized & deduplicated, keeping the original order
+pub fn normalize_all<'a>(tags: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut langs: Vec<String> = Vec::new();
+    for lang in tags.into_iter().filter_map(normalize) {
+        if !langs.contains(&lang) {
+            langs.push(lang);
+        }
+    }
+    langs
+}
+
+// Accept-Language: en-GB,en;q=0.9,de;q=0.5 -> [en, de]. Anything with q=0 is excluded, and a
+// wildcard means they'll read anything, which is the same as not filtering at all
+pub fn from_accept_language(header: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    for part in header.split(',') {
+        let mut params = part.split(';');
+        let tag = params.next().unwrap_or_default().trim();
+        let excluded = params.any(|p| {
+            p.trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        if excluded {
+            continue;
+        }
+        if tag == "*" {
+            return Vec::new();
+        }
+        tags.push(tag);
+    }
+    normalize_all(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_primary_subtag() {
+        assert_eq!(from_accept_language("en-US"), ["en"]);
+        assert_eq!(from_accept_language("pt_BR, zh-Hant-TW"), ["pt", "zh"]);
+        assert_eq!(from_accept_language("EN-gb"), ["en"]);
+    }
+
+    #[test]
+    fn orders_as_sent_and_dedupes() {
+        assert_eq!(
+            from_accept_language("en-GB,en;q=0.9,de;q=0.5"),
+            ["en", "de"]
+        );
+    }
+
+    #[test]
+    fn drops_q_zero() {
+        assert_eq!(from_accept_language("fr;q=0, en;q=0.8"), ["en"]);
+        assert_eq!(from_accept_language("fr; q=0.000, en"), ["en"]);
+        assert!(from_accept_language("de;q=0").is_empty());
+    }
+
+    #[test]
+    fn wildcard_means_everything() {
+        assert!(from_accept_language("*").is_empty());
+        assert!(from_accept_language("de, *;q=0.1").is_empty());
+        // Unless they've said they dont want it
+        assert_eq!(from_accept_language("de, *;q=0"), ["de"]);
+    }
+
+    #[test]
+    fn ignores_garbage() {
+        assert!(from_accept_language("").is_empty());
+        assert!(from_accept_language(" , ;;; ,").is_empty());
+        assert!(from_accept_language("123, e1-x, !!").is_empty());
+        assert_eq!(from_accept_language("??, en;q=nope"), ["en"]);
+    }
+}
@@ -1,5 +1,9 @@
+pub mod aturi;
+pub mod lang;
+
+pub use aturi::AtUri;
+
 #[derive(Debug)]
 pub struct FetchMessage {
-    did: String,
-    cursor: Option<String>,
+    pub did: String,
 }
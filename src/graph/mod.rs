@@ -1,22 +1,63 @@
 use neo4rs::{ConfigBuilder, Graph};
 use std::sync::Arc;
-use std::{collections::HashMap, mem, time::Instant};
+use std::{
+    collections::HashMap,
+    env, mem,
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, Mutex};
 
-use crate::common::FetchMessage;
-mod queries;
+use crate::bsky;
+use crate::common::{aturi, AtUri, FetchMessage};
+use chrono::Utc;
+pub(crate) mod queries;
 
 const Q_LIMIT: usize = 70;
+// Removes are rarer, so their queues get flushed sooner
+const RM_Q_LIMIT: usize = Q_LIMIT / 5;
+const Q_MAX_AGE: Duration = Duration::from_secs(5);
+// Dont let the ticker spin on a tiny max age
+const MIN_FLUSH_TICK: Duration = Duration::from_millis(100);
 const PURGE_TIME: u64 = 45 * 60;
+const CURSOR_ID: &str = "jetstream";
+const EDGE_DIRECTION_MIGRATION: &str = "edge_direction";
+const BACKFILL_BATCH: usize = 500;
+// Live follow events keep users up to date after the first fetch, so this is only a safety net
+const FETCH_STALE_US: i64 = 24 * 60 * 60 * 1_000_000;
+// Queues whose rows MATCH a post that may still be queued, so a size flush writes posts first
+const POST_DEPENDENTS: &[&str] = &[
+    "reply",
+    "quote",
+    "mention",
+    "tag",
+    "link",
+    "threadgate",
+    "postgate",
+    "post_update",
+];
 
 macro_rules! add_to_queue {
     ($query_name:expr, $self:ident, $( $arg:ident ),+) => {{
         let queue = match $query_name {
             "reply" =>  (&mut $self.reply_queue,queries::ADD_REPLY),
+            "quote" =>  (&mut $self.quote_queue,queries::ADD_QUOTE),
+            "mention" => (&mut $self.mention_queue,queries::ADD_MENTION),
+            "tag" =>    (&mut $self.tag_queue,queries::ADD_TAG),
+            "link" =>   (&mut $self.link_queue,queries::ADD_LINK),
+            "handle" => (&mut $self.handle_queue,queries::SET_HANDLE),
+            "post_update" => (&mut $self.post_update_queue,queries::UPDATE_POST),
+            "profile_update" => (&mut $self.profile_update_queue,queries::UPDATE_PROFILE),
+            "list_update" => (&mut $self.list_update_queue,queries::UPDATE_LIST),
             "post" =>   (&mut $self.post_queue,queries::ADD_POST),
             "repost" => (&mut $self.repost_queue,queries::ADD_REPOST),
             "follow" => (&mut $self.follow_queue,queries::ADD_FOLLOW),
             "block" =>  (&mut $self.block_queue, queries::ADD_BLOCK),
+            "list" =>   (&mut $self.list_queue, queries::ADD_LIST),
+            "listitem" => (&mut $self.listitem_queue, queries::ADD_LISTITEM),
+            "starterpack" => (&mut $self.starterpack_queue, queries::ADD_STARTERPACK),
+            "join" => (&mut $self.joined_queue, queries::ADD_JOINED_VIA),
+            "threadgate" => (&mut $self.threadgate_queue, queries::ADD_THREADGATE),
+            "postgate" => (&mut $self.postgate_queue, queries::ADD_POSTGATE),
             "like" =>   (&mut $self.like_queue,queries::ADD_LIKE),
             _ => panic!("unknown query name")
         };
@@ -27,16 +68,25 @@ macro_rules! add_to_queue {
         )*
 
         // Check if the queue is full
-        if queue.0.len() >= Q_LIMIT {
+        if queue.0.len() >= $self.config.add_limit {
             let _lock = $self.purge_spin.lock().await;
             queue.0.push(params);
 
             let n = Instant::now();
 
             // Move queue values without copying
-            let q = mem::take(queue.0);
-            let qry = neo4rs::query(queue.1).param(&pluralize($query_name), q);
-            match  $self.inner.run(qry).await{
+            let q = queue.0.take();
+            let len = q.len();
+            let query = queue.1;
+            // The posts these point at are usually still queued, and MATCH would just skip them
+            if POST_DEPENDENTS.contains(&$query_name) && !$self.post_queue.is_empty() {
+                let posts = $self.post_queue.take();
+                if let Err(e) = $self.writer.write("post", queries::ADD_POST, posts).await {
+                    println!("Error on query post");
+                    return Err(e);
+                }
+            }
+            match  $self.writer.write($query_name, query, q).await{
                 Ok(_) => {},
                 Err(e) => {
                     println!("Error on query {}", $query_name);
@@ -51,14 +101,14 @@ macro_rules! add_to_queue {
                     "Slow query {}: {}ms (~{}/s))",
                     stringify!($query_name),
                     el,
-                    (1000000000 / n.elapsed().as_nanos()) as f64 * Q_LIMIT as f64
+                    (1000000000 / n.elapsed().as_nanos()) as f64 * len as f64
                 );
-                return Ok((true));
+                return Ok(true);
             }
-            Ok((false))
+            Ok(false)
         } else {
             queue.0.push(params);
-            Ok((false))
+            Ok(false)
         }
     }};
 }
@@ -71,6 +121,11 @@ macro_rules! remove_from_queue {
             "repost" => (&mut $self.rm_repost_queue,queries::REMOVE_REPOST),
             "follow" => (&mut $self.rm_follow_queue,queries::REMOVE_FOLLOW),
             "block" =>  (&mut $self.rm_block_queue, queries::REMOVE_BLOCK),
+            "list" =>   (&mut $self.rm_list_queue, queries::REMOVE_LIST),
+            "listitem" => (&mut $self.rm_listitem_queue, queries::REMOVE_LISTITEM),
+            "starterpack" => (&mut $self.rm_starterpack_queue, queries::REMOVE_STARTERPACK),
+            "threadgate" => (&mut $self.rm_threadgate_queue, queries::REMOVE_THREADGATE),
+            "postgate" => (&mut $self.rm_postgate_queue, queries::REMOVE_POSTGATE),
             "like" =>   (&mut $self.rm_like_queue,queries::REMOVE_LIKE),
             _ => panic!("unknown query name")
         };
@@ -81,7 +136,7 @@ macro_rules! remove_from_queue {
         )*
 
         // Check if the queue is full
-        if queue.0.len() >= (Q_LIMIT / 5) { //rarer
+        if queue.0.len() >= $self.config.rm_limit {
             let _lock = $self.purge_spin.lock().await;
             queue.0.push(params);
 
@@ -89,9 +144,9 @@ macro_rules! remove_from_queue {
 
 
             // Move queue values without copying
-            let q = mem::take(queue.0);
-            let qry = neo4rs::query(queue.1).param(&pluralize($query_name), q);
-            match  $self.inner.run(qry).await{
+            let q = queue.0.take();
+            let len = q.len();
+            match  $self.writer.write($query_name, queue.1, q).await{
                 Ok(_) => {},
                 Err(e) => {
                     println!("Error on query rm_{}", $query_name);
@@ -106,35 +161,178 @@ macro_rules! remove_from_queue {
                     "Slow query REMOVE {}: {}ms (~{}/s))",
                     stringify!($query_name),
                     el,
-                    (1000000000 / n.elapsed().as_nanos()) as f64 * Q_LIMIT as f64
+                    (1000000000 / n.elapsed().as_nanos()) as f64 * len as f64
                 );
-                return Ok((true));
+                return Ok(true);
             }
-            Ok((false))
+            Ok(false)
 
         } else {
             queue.0.push(params);
-            Ok((false))
+            Ok(false)
         }
     }};
 }
 
+// When queues get written out: whichever of size or age is hit first
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub add_limit: usize,
+    pub rm_limit: usize,
+    pub max_age: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            add_limit: Q_LIMIT,
+            rm_limit: RM_Q_LIMIT,
+            max_age: Q_MAX_AGE,
+        }
+    }
+}
+
+impl QueueConfig {
+    // QUEUE_ADD_LIMIT, QUEUE_RM_LIMIT & QUEUE_MAX_AGE_MS override the defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let var = |name: &str| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|v| *v > 0)
+        };
+        Self {
+            add_limit: var("QUEUE_ADD_LIMIT").map_or(default.add_limit, |v| v as usize),
+            rm_limit: var("QUEUE_RM_LIMIT").map_or(default.rm_limit, |v| v as usize),
+            max_age: var("QUEUE_MAX_AGE_MS").map_or(default.max_age, Duration::from_millis),
+        }
+    }
+
+    // How often to check for stale queues, often enough that nothing overshoots max_age by much
+    pub fn flush_tick(&self) -> Duration {
+        (self.max_age / 4).max(MIN_FLUSH_TICK)
+    }
+}
+
+// Pending writes for one query, along with when the oldest of them turned up
+#[derive(Default)]
+struct WriteQueue {
+    items: Vec<HashMap<String, String>>,
+    since: Option<Instant>,
+}
+
+impl WriteQueue {
+    fn push(&mut self, params: HashMap<String, String>) {
+        if self.items.is_empty() {
+            self.since = Some(Instant::now());
+        }
+        self.items.push(params);
+    }
+
+    // Move queue values without copying
+    fn take(&mut self) -> Vec<HashMap<String, String>> {
+        self.since = None;
+        mem::take(&mut self.items)
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn is_stale(&self, max_age: Duration) -> bool {
+        match self.since {
+            Some(s) => s.elapsed() >= max_age,
+            None => false,
+        }
+    }
+}
+
+// Query name & rows, in the order they were written
+#[cfg(test)]
+pub(crate) type Written = Vec<(String, Vec<HashMap<String, String>>)>;
+
+// Where queued rows end up. Under test there's no database, it just keeps what it was handed
+struct QueueWriter {
+    conn: Graph,
+    #[cfg(test)]
+    written: std::sync::Mutex<Written>,
+}
+
+impl QueueWriter {
+    fn new(conn: Graph) -> Self {
+        Self {
+            conn,
+            #[cfg(test)]
+            written: Default::default(),
+        }
+    }
+
+    #[cfg(not(test))]
+    async fn write(
+        &self,
+        name: &str,
+        query: &str,
+        rows: Vec<HashMap<String, String>>,
+    ) -> Result<(), neo4rs::Error> {
+        let qry = neo4rs::query(query).param(&pluralize(name), rows);
+        self.conn.run(qry).await
+    }
+
+    #[cfg(test)]
+    async fn write(
+        &self,
+        name: &str,
+        _query: &str,
+        rows: Vec<HashMap<String, String>>,
+    ) -> Result<(), neo4rs::Error> {
+        let _ = &self.conn;
+        self.written.lock().unwrap().push((name.to_owned(), rows));
+        Ok(())
+    }
+}
+
 pub struct GraphModel {
     inner: Graph,
+    writer: QueueWriter,
     purge_spin: Arc<Mutex<()>>,
-    like_queue: Vec<HashMap<String, String>>,
-    post_queue: Vec<HashMap<String, String>>,
-    reply_queue: Vec<HashMap<String, String>>,
-    repost_queue: Vec<HashMap<String, String>>,
-    follow_queue: Vec<HashMap<String, String>>,
-    block_queue: Vec<HashMap<String, String>>,
-
-    rm_like_queue: Vec<HashMap<String, String>>,
-    rm_post_queue: Vec<HashMap<String, String>>,
-    rm_reply_queue: Vec<HashMap<String, String>>,
-    rm_repost_queue: Vec<HashMap<String, String>>,
-    rm_follow_queue: Vec<HashMap<String, String>>,
-    rm_block_queue: Vec<HashMap<String, String>>,
+    config: QueueConfig,
+    like_queue: WriteQueue,
+    post_queue: WriteQueue,
+    reply_queue: WriteQueue,
+    quote_queue: WriteQueue,
+    mention_queue: WriteQueue,
+    tag_queue: WriteQueue,
+    link_queue: WriteQueue,
+    handle_queue: WriteQueue,
+    post_update_queue: WriteQueue,
+    profile_update_queue: WriteQueue,
+    list_update_queue: WriteQueue,
+    repost_queue: WriteQueue,
+    follow_queue: WriteQueue,
+    block_queue: WriteQueue,
+    list_queue: WriteQueue,
+    listitem_queue: WriteQueue,
+    starterpack_queue: WriteQueue,
+    joined_queue: WriteQueue,
+    threadgate_queue: WriteQueue,
+    postgate_queue: WriteQueue,
+
+    rm_like_queue: WriteQueue,
+    rm_post_queue: WriteQueue,
+    rm_reply_queue: WriteQueue,
+    rm_repost_queue: WriteQueue,
+    rm_follow_queue: WriteQueue,
+    rm_block_queue: WriteQueue,
+    rm_list_queue: WriteQueue,
+    rm_listitem_queue: WriteQueue,
+    rm_starterpack_queue: WriteQueue,
+    rm_threadgate_queue: WriteQueue,
+    rm_postgate_queue: WriteQueue,
 }
 
 pub async fn kickoff_purge(spin: Arc<Mutex<()>>, conn: Graph) -> Result<(), neo4rs::Error> {
@@ -153,17 +351,95 @@ pub async fn listen_channel(
     conn: Graph,
     mut recv: mpsc::Receiver<FetchMessage>,
 ) -> Result<(), neo4rs::Error> {
+    let client = bsky::XrpcClient::from_env();
     loop {
-        let did = match recv.recv().await {
+        let msg = match recv.recv().await {
             Some(s) => s,
-            None => continue,
+            // Server side has gone away, nothing more will turn up
+            None => return Ok(()),
         };
 
-        println!("Got event for {:?}", did);
+        // One bad user shouldnt take the worker down with it, the next request will retry them
+        if let Err(e) = backfill(&write_lock, &conn, &client, &msg.did).await {
+            println!("Error backfilling follow graph for {}: {}", msg.did, e);
+        }
+    }
+}
 
-        // call getFollowers & getFollowing
-        // then queue them to write to the db - acquire the lock and write
+async fn backfill(
+    write_lock: &Mutex<()>,
+    conn: &Graph,
+    client: &bsky::XrpcClient,
+    did: &str,
+) -> Result<(), neo4rs::Error> {
+    // Cheap to check, and requests for the same user pile up while we're fetching them
+    let qry = neo4rs::query(queries::GET_USER_FETCHED).param("did", did);
+    let mut res = conn.execute(qry).await?;
+    if let Some(row) = res.next().await? {
+        if let Ok(fetched) = row.get::<i64>("fetched") {
+            if Utc::now().timestamp_micros() - fetched < FETCH_STALE_US {
+                return Ok(());
+            }
+        }
     }
+
+    println!("Backfilling follow graph for {}", did);
+    let n = Instant::now();
+    // Fetch both before writing anything, so a failure part way doesnt mark them as done
+    let follows = match bsky::get_follows(did, client).await {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Error fetching follows for {}: {}", did, e);
+            return Ok(());
+        }
+    };
+    let followers = match bsky::get_followers(did, client).await {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Error fetching followers for {}: {}", did, e);
+            return Ok(());
+        }
+    };
+
+    let edges = follows
+        .iter()
+        .map(|f| (did.to_owned(), f.clone()))
+        .chain(followers.iter().map(|out| (out.clone(), did.to_owned())))
+        .map(|(out, did)| HashMap::from([("out".to_owned(), out), ("did".to_owned(), did)]))
+        .collect::<Vec<_>>();
+    let count = edges.len();
+
+    // Small batches so we dont hold the write lock over ingest for too long
+    for batch in edges.chunks(BACKFILL_BATCH) {
+        let _lock = write_lock.lock().await;
+        let qry = neo4rs::query(queries::BACKFILL_FOLLOWS).param("follows", batch.to_vec());
+        conn.run(qry).await?;
+    }
+
+    // Live unfollows take care of edges with an rkey, these only clear out stale backfilled ones
+    {
+        let _lock = write_lock.lock().await;
+        let qry = neo4rs::query(queries::PRUNE_BACKFILLED_FOLLOWS)
+            .param("did", did)
+            .param("follows", follows);
+        conn.run(qry).await?;
+        let qry = neo4rs::query(queries::PRUNE_BACKFILLED_FOLLOWERS)
+            .param("did", did)
+            .param("followers", followers);
+        conn.run(qry).await?;
+    }
+
+    let qry = neo4rs::query(queries::SET_USER_FETCHED)
+        .param("did", did)
+        .param("fetched", Utc::now().timestamp_micros());
+    conn.run(qry).await?;
+    println!(
+        "Backfilled {} follow edges for {} in {}ms",
+        count,
+        did,
+        n.elapsed().as_millis()
+    );
+    Ok(())
 }
 
 impl GraphModel {
@@ -175,11 +451,17 @@ impl GraphModel {
         self.inner.clone()
     }
 
+    // For writes from outside ingest, so they dont collide with a queue flush or purge
+    pub fn write_lock(&self) -> Arc<Mutex<()>> {
+        self.purge_spin.clone()
+    }
+
     pub async fn new(
         uri: &str,
         user: &str,
         pass: &str,
         recv: mpsc::Receiver<FetchMessage>,
+        config: QueueConfig,
     ) -> Result<Self, neo4rs::Error> {
         let cfg = ConfigBuilder::new()
             .uri(uri)
@@ -194,6 +476,28 @@ impl GraphModel {
         inner
             .run(neo4rs::query("CREATE INDEX ON :Post(rkey)"))
             .await?;
+        inner
+            .run(neo4rs::query("CREATE INDEX ON :Post(did)"))
+            .await?;
+        inner
+            .run(neo4rs::query("CREATE INDEX ON :Post(langs)"))
+            .await?;
+        inner
+            .run(neo4rs::query("CREATE INDEX ON :Hashtag(tag)"))
+            .await?;
+        inner
+            .run(neo4rs::query("CREATE INDEX ON :List(rkey)"))
+            .await?;
+        inner
+            .run(neo4rs::query("CREATE INDEX ON :StarterPack(rkey)"))
+            .await?;
+        inner
+            .run(neo4rs::query("CREATE INDEX ON :Domain(name)"))
+            .await?;
+        // Posts used to be keyed on rkey alone, give any from before that their author's did.
+        // Does nothing once everything has one
+        inner.run(neo4rs::query(queries::MIGRATE_POST_DIDS)).await?;
+        migrate_edge_direction(&inner).await?;
 
         // Set off background job to do whatever cleaning we want
         let purge_spin = Arc::new(Mutex::new(()));
@@ -219,34 +523,305 @@ impl GraphModel {
             };
         });
 
-        let res = Self {
+        Ok(Self::from_conn(inner, purge_spin, config))
+    }
+
+    fn from_conn(inner: Graph, purge_spin: Arc<Mutex<()>>, config: QueueConfig) -> Self {
+        Self {
+            writer: QueueWriter::new(inner.clone()),
             inner,
             purge_spin,
+            config,
             like_queue: Default::default(),
             post_queue: Default::default(),
             follow_queue: Default::default(),
             repost_queue: Default::default(),
             block_queue: Default::default(),
+            list_queue: Default::default(),
+            listitem_queue: Default::default(),
+            starterpack_queue: Default::default(),
+            joined_queue: Default::default(),
+            threadgate_queue: Default::default(),
+            postgate_queue: Default::default(),
             reply_queue: Default::default(),
+            quote_queue: Default::default(),
+            mention_queue: Default::default(),
+            tag_queue: Default::default(),
+            link_queue: Default::default(),
+            handle_queue: Default::default(),
+            post_update_queue: Default::default(),
+            profile_update_queue: Default::default(),
+            list_update_queue: Default::default(),
 
             rm_like_queue: Default::default(),
             rm_post_queue: Default::default(),
             rm_follow_queue: Default::default(),
             rm_repost_queue: Default::default(),
             rm_block_queue: Default::default(),
+            rm_list_queue: Default::default(),
+            rm_listitem_queue: Default::default(),
+            rm_starterpack_queue: Default::default(),
+            rm_threadgate_queue: Default::default(),
+            rm_postgate_queue: Default::default(),
             rm_reply_queue: Default::default(),
+        }
+    }
+
+    // No database behind this one, queue writes are only recorded for `written` to hand back
+    #[cfg(test)]
+    pub(crate) async fn offline(config: QueueConfig) -> Self {
+        let inner = Graph::new("127.0.0.1:7687", "", "").await.unwrap();
+        Self::from_conn(inner, Arc::new(Mutex::new(())), config)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn written(&self) -> Written {
+        self.writer.written.lock().unwrap().clone()
+    }
+
+    pub fn flush_tick(&self) -> Duration {
+        self.config.flush_tick()
+    }
+
+    // Write out everything sitting in the queues, regardless of how full they are.
+    pub async fn flush_all(&mut self) -> Result<(), neo4rs::Error> {
+        self.flush(None).await
+    }
+
+    // Write out any queue whose oldest entry has been waiting longer than the max age,
+    // so quiet queues like blocks & deletes still show up in the graph
+    pub async fn flush_stale(&mut self) -> Result<(), neo4rs::Error> {
+        let max_age = self.config.max_age;
+        self.flush(Some(max_age)).await
+    }
+
+    // Adds go first so removes can see anything they refer to, and posts before anything
+    // that points at them. A stale queue takes every queue ahead of it along, so what it
+    // depends on is always written first.
+    async fn flush(&mut self, max_age: Option<Duration>) -> Result<(), neo4rs::Error> {
+        let _lock = self.purge_spin.lock().await;
+        let queues = [
+            ("post", &mut self.post_queue, queries::ADD_POST),
+            ("reply", &mut self.reply_queue, queries::ADD_REPLY),
+            ("quote", &mut self.quote_queue, queries::ADD_QUOTE),
+            ("mention", &mut self.mention_queue, queries::ADD_MENTION),
+            ("tag", &mut self.tag_queue, queries::ADD_TAG),
+            ("link", &mut self.link_queue, queries::ADD_LINK),
+            ("repost", &mut self.repost_queue, queries::ADD_REPOST),
+            ("like", &mut self.like_queue, queries::ADD_LIKE),
+            ("follow", &mut self.follow_queue, queries::ADD_FOLLOW),
+            ("block", &mut self.block_queue, queries::ADD_BLOCK),
+            ("list", &mut self.list_queue, queries::ADD_LIST),
+            ("listitem", &mut self.listitem_queue, queries::ADD_LISTITEM),
+            (
+                "starterpack",
+                &mut self.starterpack_queue,
+                queries::ADD_STARTERPACK,
+            ),
+            ("join", &mut self.joined_queue, queries::ADD_JOINED_VIA),
+            (
+                "threadgate",
+                &mut self.threadgate_queue,
+                queries::ADD_THREADGATE,
+            ),
+            ("postgate", &mut self.postgate_queue, queries::ADD_POSTGATE),
+            ("handle", &mut self.handle_queue, queries::SET_HANDLE),
+            // After the adds, an edit can only land on something that already exists
+            (
+                "post_update",
+                &mut self.post_update_queue,
+                queries::UPDATE_POST,
+            ),
+            (
+                "profile_update",
+                &mut self.profile_update_queue,
+                queries::UPDATE_PROFILE,
+            ),
+            (
+                "list_update",
+                &mut self.list_update_queue,
+                queries::UPDATE_LIST,
+            ),
+            ("reply", &mut self.rm_reply_queue, queries::REMOVE_REPLY),
+            ("repost", &mut self.rm_repost_queue, queries::REMOVE_REPOST),
+            ("like", &mut self.rm_like_queue, queries::REMOVE_LIKE),
+            ("follow", &mut self.rm_follow_queue, queries::REMOVE_FOLLOW),
+            ("block", &mut self.rm_block_queue, queries::REMOVE_BLOCK),
+            (
+                "listitem",
+                &mut self.rm_listitem_queue,
+                queries::REMOVE_LISTITEM,
+            ),
+            (
+                "starterpack",
+                &mut self.rm_starterpack_queue,
+                queries::REMOVE_STARTERPACK,
+            ),
+            (
+                "threadgate",
+                &mut self.rm_threadgate_queue,
+                queries::REMOVE_THREADGATE,
+            ),
+            (
+                "postgate",
+                &mut self.rm_postgate_queue,
+                queries::REMOVE_POSTGATE,
+            ),
+            ("list", &mut self.rm_list_queue, queries::REMOVE_LIST),
+            ("post", &mut self.rm_post_queue, queries::REMOVE_POST),
+        ];
+
+        let last = match max_age {
+            Some(age) => match queues.iter().rposition(|q| q.1.is_stale(age)) {
+                Some(i) => i,
+                None => return Ok(()),
+            },
+            None => queues.len() - 1,
+        };
+
+        for (query_name, queue, query) in queues.into_iter().take(last + 1) {
+            if queue.is_empty() {
+                continue;
+            }
+            let q = queue.take();
+            if let Err(e) = self.writer.write(query_name, query, q).await {
+                println!("Error flushing {}", query_name);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Only call this after flush_all, otherwise a restart can skip over whatever was still queued
+    pub async fn save_cursor(&self, time_us: i64) -> Result<(), neo4rs::Error> {
+        let qry = neo4rs::query(queries::SET_CURSOR)
+            .param("id", CURSOR_ID)
+            .param("time_us", time_us);
+        self.inner.run(qry).await
+    }
+
+    pub async fn load_cursor(&self) -> Result<Option<i64>, neo4rs::Error> {
+        let qry = neo4rs::query(queries::GET_CURSOR).param("id", CURSOR_ID);
+        let mut res = self.inner.execute(qry).await?;
+        match res.next().await? {
+            Some(row) => Ok(row.get::<i64>("time_us").ok()),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_handle(&mut self, did: String, handle: String) -> Result<bool, neo4rs::Error> {
+        add_to_queue!("handle", self, did, handle)
+    }
+
+    // Rare enough to write straight away rather than queue
+    pub async fn set_account_state(
+        &mut self,
+        did: String,
+        state: AccountState,
+    ) -> Result<(), neo4rs::Error> {
+        match state {
+            AccountState::Active | AccountState::Hidden => {
+                let _lock = self.purge_spin.lock().await;
+                let qry = neo4rs::query(queries::SET_USER_ACTIVE)
+                    .param("did", did)
+                    .param("active", state == AccountState::Active);
+                self.inner.run(qry).await
+            }
+            AccountState::Deleted => {
+                // Otherwise whatever is still queued for them would come straight back
+                self.flush_all().await?;
+                let _lock = self.purge_spin.lock().await;
+                println!("Purging deleted account {did}");
+                let qry = neo4rs::query(queries::PURGE_ACCOUNT).param("did", did);
+                self.inner.run(qry).await
+            }
+        }
+    }
+
+    // Replaces the post's media, languages, quote & facets with whatever the edit has now
+    pub async fn update_post(
+        &mut self,
+        did: String,
+        rkey: String,
+        content: PostContent,
+    ) -> Result<bool, neo4rs::Error> {
+        let has_alt = if content.media.has_alt {
+            "y".to_owned()
+        } else {
+            "n".to_owned()
         };
+        let media_kind = content.media.kind.to_owned();
+        let image_count = format!("{}", content.media.image_count);
+        let link_domain = content.media.link_domain.unwrap_or_default();
+        // Lists go in comma separated, none of these can have a comma in them
+        let langs = content.langs.join(",");
+        let mentions = content.mentions.join(",");
+        let tags = content.tags.join(",");
+        let domains = content.domains.join(",");
+        let (quote_did, quote_rkey) = match content.quote {
+            Some(q) => (q.authority, q.rkey),
+            None => (String::new(), String::new()),
+        };
+
+        add_to_queue!(
+            "post_update",
+            self,
+            did,
+            rkey,
+            media_kind,
+            image_count,
+            has_alt,
+            link_domain,
+            langs,
+            mentions,
+            tags,
+            domains,
+            quote_did,
+            quote_rkey
+        )
+    }
+
+    pub async fn update_profile(
+        &mut self,
+        did: String,
+        display_name: String,
+        description: String,
+    ) -> Result<bool, neo4rs::Error> {
+        add_to_queue!("profile_update", self, did, display_name, description)
+    }
 
-        Ok(res)
+    pub async fn update_list(
+        &mut self,
+        did: String,
+        rkey: String,
+        name: String,
+        purpose: String,
+        description: String,
+    ) -> Result<bool, neo4rs::Error> {
+        add_to_queue!("list_update", self, did, rkey, name, purpose, description)
     }
 
     pub async fn add_reply(
         &mut self,
         did: String,
         rkey: String,
-        parent: String,
+        parent: AtUri,
+        root: AtUri,
     ) -> Result<bool, neo4rs::Error> {
-        add_to_queue!("reply", self, did, rkey, parent)
+        let parent_did = parent.authority;
+        let parent_rkey = parent.rkey;
+        let root_did = root.authority;
+        let root_rkey = root.rkey;
+        add_to_queue!(
+            "reply",
+            self,
+            did,
+            rkey,
+            parent_did,
+            parent_rkey,
+            root_did,
+            root_rkey
+        )
     }
 
     pub async fn add_post(
@@ -255,7 +830,8 @@ impl GraphModel {
         rkey: String,
         timestamp: &i64,
         is_reply: bool,
-        is_image: bool,
+        media: PostMedia,
+        langs: Vec<String>,
     ) -> Result<bool, neo4rs::Error> {
         let is_reply = if is_reply {
             "y".to_owned()
@@ -263,24 +839,83 @@ impl GraphModel {
             "n".to_owned()
         };
 
-        let is_image = if is_image {
+        let has_alt = if media.has_alt {
             "y".to_owned()
         } else {
             "n".to_owned()
         };
 
         let timestamp = format! {"{timestamp}"};
+        let media_kind = media.kind.to_owned();
+        let image_count = format!("{}", media.image_count);
+        let link_domain = media.link_domain.unwrap_or_default();
+        // Queue params are all strings, ADD_POST splits this back into a list
+        let langs = langs.join(",");
 
-        add_to_queue!("post", self, did, rkey, is_reply, is_image, timestamp)
+        add_to_queue!(
+            "post",
+            self,
+            did,
+            rkey,
+            is_reply,
+            timestamp,
+            media_kind,
+            image_count,
+            has_alt,
+            link_domain,
+            langs
+        )
+    }
+
+    // `did`/`rkey` is the quoting post, `subject` the post it quotes
+    pub async fn add_quote(
+        &mut self,
+        did: String,
+        rkey: String,
+        subject: AtUri,
+    ) -> Result<bool, neo4rs::Error> {
+        let subject_did = subject.authority;
+        let subject_rkey = subject.rkey;
+        add_to_queue!("quote", self, did, rkey, subject_did, subject_rkey)
+    }
+
+    // `subject` is the mentioned user's did
+    pub async fn add_mention(
+        &mut self,
+        did: String,
+        rkey: String,
+        subject: String,
+    ) -> Result<bool, neo4rs::Error> {
+        add_to_queue!("mention", self, did, rkey, subject)
+    }
+
+    pub async fn add_tag(
+        &mut self,
+        did: String,
+        rkey: String,
+        tag: String,
+    ) -> Result<bool, neo4rs::Error> {
+        add_to_queue!("tag", self, did, rkey, tag)
+    }
+
+    pub async fn add_link(
+        &mut self,
+        did: String,
+        rkey: String,
+        domain: String,
+    ) -> Result<bool, neo4rs::Error> {
+        add_to_queue!("link", self, did, rkey, domain)
     }
 
     pub async fn add_repost(
         &mut self,
         did: String,
-        rkey_parent: String,
+        subject: AtUri,
         rkey: String,
     ) -> Result<bool, neo4rs::Error> {
-        add_to_queue!("repost", self, did, rkey, rkey_parent)
+        let subject_did = subject.authority;
+        let subject_rkey = subject.rkey;
+        add_to_queue!("repost", self, did, rkey, subject_did, subject_rkey)
     }
 
     pub async fn add_follow(
@@ -304,10 +939,116 @@ impl GraphModel {
     pub async fn add_like(
         &mut self,
         did: String,
-        rkey_parent: String,
+        subject: AtUri,
+        rkey: String,
+    ) -> Result<bool, neo4rs::Error> {
+        let subject_did = subject.authority;
+        let subject_rkey = subject.rkey;
+        add_to_queue!("like", self, did, rkey, subject_did, subject_rkey)
+    }
+
+    // purpose is curate, mod or reference
+    pub async fn add_list(
+        &mut self,
+        did: String,
+        rkey: String,
+        name: String,
+        purpose: String,
+        description: String,
+    ) -> Result<bool, neo4rs::Error> {
+        add_to_queue!("list", self, did, rkey, name, purpose, description)
+    }
+
+    // `subject` is the did being added to `list`
+    pub async fn add_listitem(
+        &mut self,
+        did: String,
+        rkey: String,
+        list: AtUri,
+        subject: String,
+    ) -> Result<bool, neo4rs::Error> {
+        let list_rkey = list.rkey;
+        add_to_queue!("listitem", self, did, rkey, list_rkey, subject)
+    }
+
+    // Also how a pack gets moved to a different list, the old one is let go
+    pub async fn add_starterpack(
+        &mut self,
+        did: String,
         rkey: String,
+        name: String,
+        list: AtUri,
     ) -> Result<bool, neo4rs::Error> {
-        add_to_queue!("like", self, did, rkey, rkey_parent)
+        let list_did = list.authority;
+        let list_rkey = list.rkey;
+        add_to_queue!("starterpack", self, did, rkey, name, list_did, list_rkey)
+    }
+
+    pub async fn add_joined_via(
+        &mut self,
+        did: String,
+        pack: AtUri,
+    ) -> Result<bool, neo4rs::Error> {
+        let pack_did = pack.authority;
+        let pack_rkey = pack.rkey;
+        add_to_queue!("join", self, did, pack_did, pack_rkey)
+    }
+
+    // `rkey` is the gated post's, which is also the gate's
+    pub async fn add_threadgate(
+        &mut self,
+        did: String,
+        rkey: String,
+        restricted: bool,
+    ) -> Result<bool, neo4rs::Error> {
+        let restricted = if restricted {
+            "y".to_owned()
+        } else {
+            "n".to_owned()
+        };
+        add_to_queue!("threadgate", self, did, rkey, restricted)
+    }
+
+    // `detached` are quote posts the author has pulled their post out of
+    pub async fn add_postgate(
+        &mut self,
+        did: String,
+        rkey: String,
+        quotes_disabled: bool,
+        detached: Vec<AtUri>,
+    ) -> Result<bool, neo4rs::Error> {
+        let quotes_disabled = if quotes_disabled {
+            "y".to_owned()
+        } else {
+            "n".to_owned()
+        };
+        // AT-URIs cant have commas in them
+        let detached = detached
+            .iter()
+            .map(|u| u.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        add_to_queue!("postgate", self, did, rkey, quotes_disabled, detached)
+    }
+
+    pub async fn rm_threadgate(
+        &mut self,
+        did: String,
+        rkey: String,
+    ) -> Result<bool, neo4rs::Error> {
+        remove_from_queue!("threadgate", self, did, rkey)
+    }
+
+    pub async fn rm_postgate(&mut self, did: String, rkey: String) -> Result<bool, neo4rs::Error> {
+        remove_from_queue!("postgate", self, did, rkey)
+    }
+
+    pub async fn rm_starterpack(
+        &mut self,
+        did: String,
+        rkey: String,
+    ) -> Result<bool, neo4rs::Error> {
+        remove_from_queue!("starterpack", self, did, rkey)
     }
 
     pub async fn rm_post(&mut self, did: String, rkey: String) -> Result<bool, neo4rs::Error> {
@@ -330,28 +1071,304 @@ impl GraphModel {
         remove_from_queue!("block", self, did, rkey)
     }
 
+    pub async fn rm_list(&mut self, did: String, rkey: String) -> Result<bool, neo4rs::Error> {
+        remove_from_queue!("list", self, did, rkey)
+    }
+
+    pub async fn rm_listitem(&mut self, did: String, rkey: String) -> Result<bool, neo4rs::Error> {
+        remove_from_queue!("listitem", self, did, rkey)
+    }
+
     pub async fn rm_reply(&mut self, did: String, rkey: String) -> Result<bool, neo4rs::Error> {
         remove_from_queue!("reply", self, did, rkey)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccountState {
+    Active,
+    // Deactivated, suspended or taken down, their posts stay out of feeds until they're back
+    Hidden,
+    Deleted,
+}
+
+// The parts of a post that an edit can change
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PostContent {
+    pub media: PostMedia,
+    pub langs: Vec<String>,
+    pub quote: Option<AtUri>,
+    pub mentions: Vec<String>,
+    pub tags: Vec<String>,
+    pub domains: Vec<String>,
+}
+
+// What a post has attached, flattened down to what ranking cares about
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostMedia {
+    // none, images, video or external
+    pub kind: &'static str,
+    pub image_count: usize,
+    // Every image/video has alt text. Always false with nothing attached
+    pub has_alt: bool,
+    pub link_domain: Option<String>,
+}
+
+impl Default for PostMedia {
+    fn default() -> Self {
+        Self {
+            kind: "none",
+            image_count: 0,
+            has_alt: false,
+            link_domain: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedPost {
+    pub did: String,
+    pub rkey: String,
+    // What the feed is ordered & paged by, usually just when it was posted
+    pub timestamp: i64,
+    // When it was actually posted, whatever the feed ordered it by
+    pub posted: i64,
+    // Set when the post is there because someone reposted it
+    pub repost: Option<AtUri>,
+    // Whatever the query wants to remember about why it picked this post
+    pub context: Option<String>,
+    // Replies are restricted, only discovery feeds bother to say
+    pub gated: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Engagement {
+    pub likes: i64,
+    pub reposts: i64,
+    pub replies: i64,
+    pub quotes: i64,
+}
+
+// Engagement counts for `posts`, keyed on (did, rkey). Posts we dont have are left out
+pub async fn get_engagement(
+    conn: &Graph,
+    posts: &[FeedPost],
+) -> Result<HashMap<(String, String), Engagement>, neo4rs::Error> {
+    let items = posts
+        .iter()
+        .map(|p| {
+            HashMap::from([
+                ("did".to_owned(), p.did.clone()),
+                ("rkey".to_owned(), p.rkey.clone()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let qry = neo4rs::query(queries::GET_POST_ENGAGEMENT).param("posts", items);
+    let mut res = conn.execute(qry).await?;
+
+    let mut engagement = HashMap::new();
+    while let Some(row) = res.next().await? {
+        let (did, rkey) = match (row.get::<String>("did"), row.get::<String>("rkey")) {
+            (Ok(d), Ok(r)) => (d, r),
+            _ => continue,
+        };
+        engagement.insert(
+            (did, rkey),
+            Engagement {
+                likes: row.get::<i64>("likes").unwrap_or_default(),
+                reposts: row.get::<i64>("reposts").unwrap_or_default(),
+                replies: row.get::<i64>("replies").unwrap_or_default(),
+                quotes: row.get::<i64>("quotes").unwrap_or_default(),
+            },
+        );
+    }
+    Ok(engagement)
+}
+
+// Net show more (positive) or less (negative) from `did` for each author in `posts`.
+// Authors they never gave any are left out
+pub async fn get_author_feedback(
+    conn: &Graph,
+    did: &str,
+    posts: &[FeedPost],
+) -> Result<HashMap<String, i64>, neo4rs::Error> {
+    let mut authors = posts.iter().map(|p| p.did.clone()).collect::<Vec<_>>();
+    authors.sort();
+    authors.dedup();
+    let qry = neo4rs::query(queries::GET_AUTHOR_FEEDBACK)
+        .param("did", did)
+        .param("authors", authors);
+    let mut res = conn.execute(qry).await?;
+
+    let mut feedback = HashMap::new();
+    while let Some(row) = res.next().await? {
+        if let (Ok(author), Ok(signal)) = (row.get::<String>("did"), row.get::<i64>("signal")) {
+            feedback.insert(author, signal);
+        }
+    }
+    Ok(feedback)
+}
+
+// What a requester told us about a post we served them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feedback {
+    Less,
+    More,
+    Seen,
+    Clicked,
+}
+
+#[derive(Debug, Clone)]
+pub struct Interaction {
+    pub post: AtUri,
+    pub feedback: Feedback,
+    // The feedContext the post was served with
+    pub context: Option<String>,
+}
+
+// Stores interactions from `did`, posts we dont have are dropped
+pub async fn add_interactions(
+    conn: &Graph,
+    write_lock: &Mutex<()>,
+    did: &str,
+    interactions: &[Interaction],
+) -> Result<(), neo4rs::Error> {
+    let items = interactions
+        .iter()
+        .map(|i| {
+            let kind = match i.feedback {
+                Feedback::Less => "less",
+                Feedback::More => "more",
+                Feedback::Seen => "seen",
+                Feedback::Clicked => "click",
+            };
+            HashMap::from([
+                ("did".to_owned(), i.post.authority.clone()),
+                ("rkey".to_owned(), i.post.rkey.clone()),
+                ("kind".to_owned(), kind.to_owned()),
+                ("context".to_owned(), i.context.clone().unwrap_or_default()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let qry = neo4rs::query(queries::ADD_INTERACTIONS)
+        .param("did", did)
+        .param("interactions", items)
+        .param("now", Utc::now().timestamp_micros());
+    let _lock = write_lock.lock().await;
+    conn.run(qry).await
+}
+
+// FOLLOWS & BLOCKED edges were written the wrong way round until ADD_FOLLOW & add_block got fixed.
+// A flipped edge looks just like a right one, so this only ever runs once, and in one
+// transaction so a restart halfway through cant flip anything twice
+async fn migrate_edge_direction(conn: &Graph) -> Result<(), neo4rs::Error> {
+    let qry = neo4rs::query(queries::GET_MIGRATION).param("id", EDGE_DIRECTION_MIGRATION);
+    if conn.execute(qry).await?.next().await?.is_some() {
+        return Ok(());
+    }
+    println!("Flipping old FOLLOWS & BLOCKED edges");
+    let mut txn = conn.start_txn().await?;
+    txn.run(neo4rs::query(queries::MIGRATE_FOLLOW_DIRECTION))
+        .await?;
+    txn.run(neo4rs::query(queries::MIGRATE_BLOCK_DIRECTION))
+        .await?;
+    txn.run(neo4rs::query(queries::SET_MIGRATION).param("id", EDGE_DIRECTION_MIGRATION))
+        .await?;
+    txn.commit().await
+}
+
+// Runs one of the feed queries for `did`, newest first.
+// `before` is the (timestamp, rkey) of the last post already served, if any.
+// With `langs` set, only posts in one of them (or with no language at all) come back
+pub async fn get_feed_posts(
+    conn: &Graph,
+    query: &str,
+    did: &str,
+    limit: i64,
+    before: Option<(i64, String)>,
+    langs: &[String],
+) -> Result<Vec<FeedPost>, neo4rs::Error> {
+    let (before_ts, before_rkey) = before.unwrap_or((i64::MAX, String::new()));
+    let qry = neo4rs::query(query)
+        .param("did", did)
+        .param("limit", limit)
+        .param("before_ts", before_ts)
+        .param("before_rkey", before_rkey)
+        .param("langs", langs.to_vec());
+    read_feed_posts(conn, qry).await
+}
+
+// Same as get_feed_posts, but for posts by members of `list` rather than anything to do with the requester
+pub async fn get_list_posts(
+    conn: &Graph,
+    list: &AtUri,
+    limit: i64,
+    before: Option<(i64, String)>,
+    langs: &[String],
+) -> Result<Vec<FeedPost>, neo4rs::Error> {
+    let (before_ts, before_rkey) = before.unwrap_or((i64::MAX, String::new()));
+    let qry = neo4rs::query(queries::GET_LIST_POSTS)
+        .param("list_did", list.authority.as_str())
+        .param("list_rkey", list.rkey.as_str())
+        .param("limit", limit)
+        .param("before_ts", before_ts)
+        .param("before_rkey", before_rkey)
+        .param("langs", langs.to_vec());
+    read_feed_posts(conn, qry).await
+}
+
+async fn read_feed_posts(conn: &Graph, qry: neo4rs::Query) -> Result<Vec<FeedPost>, neo4rs::Error> {
+    let mut res = conn.execute(qry).await?;
+
+    let mut posts = Vec::new();
+    while let Some(row) = res.next().await? {
+        let (did, rkey, timestamp) = match (
+            row.get::<String>("did"),
+            row.get::<String>("rkey"),
+            row.get::<i64>("timestamp"),
+        ) {
+            (Ok(d), Ok(r), Ok(t)) => (d, r, t),
+            _ => continue,
+        };
+        // Only some queries return these, and they can be null when they do
+        let repost = match (
+            row.get::<String>("repost_did"),
+            row.get::<String>("repost_rkey"),
+        ) {
+            (Ok(d), Ok(r)) => Some(AtUri::new(&d, aturi::REPOST_COLLECTION, &r)),
+            _ => None,
+        };
+        posts.push(FeedPost {
+            did,
+            rkey,
+            posted: row.get::<i64>("posted").unwrap_or(timestamp),
+            timestamp,
+            repost,
+            context: row.get::<String>("context").ok(),
+            gated: row.get::<bool>("gated").unwrap_or(false),
+        });
+    }
+    Ok(posts)
+}
+
 pub fn get_post_uri(did: String, rkey: String) -> String {
-    format!("at://{did}/app.bsky.feed.post/{rkey}")
+    AtUri::new(&did, aturi::POST_COLLECTION, &rkey).to_string()
 }
+#[cfg_attr(test, allow(dead_code))]
 fn pluralize(word: &str) -> String {
     let word_len = word.len();
     let snip = &word[..word_len - 1];
     let last_char = word.chars().nth(word_len - 1).unwrap();
 
     if last_char == 'y' || word.ends_with("ay") {
-        return format!("{}ies", snip);
+        format!("{}ies", snip)
     } else if last_char == 's' || last_char == 'x' || last_char == 'z' {
-        return format!("{}es", word);
+        format!("{}es", word)
     } else if last_char == 'o' && word.ends_with("o") && !word.ends_with("oo") {
-        return format!("{}oes", snip);
+        format!("{}oes", snip)
     } else if last_char == 'u' && word.ends_with("u") {
-        return format!("{}i", snip);
+        format!("{}i", snip)
     } else {
-        return format!("{}s", word);
+        format!("{}s", word)
     }
 }
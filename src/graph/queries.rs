@@ -1,82 +1,273 @@
 pub(crate) const ADD_FOLLOW: &str = r#"
 UNWIND $follows as follow
-MERGE (u:User {did: follow.did})
-MERGE (v:User {did: follow.out})
-CREATE (u)-[r:FOLLOWS {rkey: follow.rkey }]->(v)
+MERGE (u:User {did: follow.out})
+MERGE (v:User {did: follow.did})
+MERGE (u)-[r:FOLLOWS]->(v)
+SET r.rkey = follow.rkey
 "#;
 
 pub(crate) const ADD_BLOCK: &str = r#"
 UNWIND $blocks as block
 MERGE (u:User {did: block.did})
 MERGE (v:User {did: block.blockee})
-CREATE (u)-[r:BLOCKED {rkey: block.rkey }]->(v)
+MERGE (u)-[r:BLOCKED {rkey: block.rkey }]->(v)
 "#;
 
 pub(crate) const ADD_LIKE: &str = r#"
 UNWIND $likes as like
-MATCH (p:Post) WHERE p.rkey = like.rkey_parent
+MATCH (p:Post {did: like.subject_did, rkey: like.subject_rkey})
 MERGE (u:User {did: like.did})
-
-CREATE (u)-[r:LIKES {rkey: like.rkey }]->(p)
+MERGE (u)-[r:LIKES {rkey: like.rkey }]->(p)
 "#;
 
 pub(crate) const ADD_POST: &str = r#"
 UNWIND $posts as post
 MERGE (u:User {did: post.did})
-CREATE (u)-[:POSTED {rkey : post.rkey}]->(p: Post { timestamp: post.timestamp, rkey: post.rkey, isReply: post.isReply } )
+MERGE (p:Post {did: post.did, rkey: post.rkey})
+ON CREATE SET p.timestamp = post.timestamp,
+    p.isReply = post.is_reply,
+    p.mediaKind = post.media_kind,
+    p.imageCount = toInteger(post.image_count),
+    p.hasAlt = post.has_alt = "y",
+    p.linkDomain = CASE WHEN post.link_domain = "" THEN null ELSE post.link_domain END,
+    p.langs = CASE WHEN post.langs = "" THEN [] ELSE split(post.langs, ",") END,
+    p.depth = CASE WHEN post.is_reply = "y" THEN null ELSE 0 END
+MERGE (u)-[:POSTED {rkey: post.rkey}]->(p)
 "#;
 
 pub(crate) const ADD_REPOST: &str = r#"
 UNWIND $reposts as repost
-MATCH (p:Post) WHERE p.rkey = repost.rkey_parent
+MATCH (p:Post {did: repost.subject_did, rkey: repost.subject_rkey})
 MERGE (u:User {did: repost.did})
-CREATE (u)-[r:REPOSTED {rkey: repost.rkey }]->(p)
+MERGE (u)-[r:REPOSTED {rkey: repost.rkey }]->(p)
 "#;
 
+// The reply's own Post is already in from the post queue, the parent & root may not be if we never
+// saw them. Depth is 1 under the root, otherwise one more than the parent's, and stays unset when
+// the parent's is unknown. Top level posts are depth 0, see ADD_POST
 pub(crate) const ADD_REPLY: &str = r#"
 UNWIND $replies as reply
-MATCH (p:Post) WHERE p.rkey = reply.parent
+MATCH (p:Post {did: reply.did, rkey: reply.rkey})
 MERGE (u:User {did: reply.did})
-CREATE (u)-[r:REPLIED_TO {rkey: reply.rkey }]->(p)
+WITH reply, p, u
+OPTIONAL MATCH (parent:Post {did: reply.parent_did, rkey: reply.parent_rkey})
+OPTIONAL MATCH (root:Post {did: reply.root_did, rkey: reply.root_rkey})
+FOREACH (_ IN CASE WHEN parent IS NULL THEN [] ELSE [1] END |
+    MERGE (u)-[:REPLIED_TO {rkey: reply.rkey }]->(parent)
+    MERGE (p)-[:REPLY_TO]->(parent)
+)
+FOREACH (_ IN CASE WHEN root IS NULL THEN [] ELSE [1] END |
+    MERGE (p)-[:IN_THREAD]->(root)
+)
+SET p.depth = CASE
+    WHEN reply.parent_did = reply.root_did AND reply.parent_rkey = reply.root_rkey THEN 1
+    ELSE parent.depth + 1
+END
+"#;
+
+// Deleting either post takes the edge with it, see REMOVE_POST. The quoted post's postgate
+// may already have detached this one
+pub(crate) const ADD_QUOTE: &str = r#"
+UNWIND $quotes as quote
+MATCH (p:Post {did: quote.did, rkey: quote.rkey})
+MATCH (q:Post {did: quote.subject_did, rkey: quote.subject_rkey})
+MERGE (p)-[r:QUOTES]->(q)
+SET r.detached = "at://" + p.did + "/app.bsky.feed.post/" + p.rkey IN coalesce(q.detachedQuotes, [])
+"#;
+
+// Facets. Like quotes, these go when the post does
+pub(crate) const ADD_MENTION: &str = r#"
+UNWIND $mentions as mention
+MATCH (p:Post {did: mention.did, rkey: mention.rkey})
+MERGE (u:User {did: mention.subject})
+MERGE (p)-[:MENTIONS]->(u)
+"#;
+
+pub(crate) const ADD_TAG: &str = r#"
+UNWIND $tags as tag
+MATCH (p:Post {did: tag.did, rkey: tag.rkey})
+MERGE (h:Hashtag {tag: tag.tag})
+MERGE (p)-[:TAGGED]->(h)
+"#;
+
+pub(crate) const ADD_LINK: &str = r#"
+UNWIND $links as link
+MATCH (p:Post {did: link.did, rkey: link.rkey})
+MERGE (d:Domain {name: link.domain})
+MERGE (p)-[:LINKS]->(d)
+"#;
+
+// Items can turn up for lists from before we were listening, so those get a bare List
+// that fills in if it is ever edited
+pub(crate) const ADD_LIST: &str = r#"
+UNWIND $lists as list
+MERGE (l:List {did: list.did, rkey: list.rkey})
+SET l.name = list.name, l.purpose = list.purpose, l.description = list.description
+"#;
+
+pub(crate) const ADD_LISTITEM: &str = r#"
+UNWIND $listitems as item
+MERGE (l:List {did: item.did, rkey: item.list_rkey})
+MERGE (u:User {did: item.subject})
+MERGE (l)-[r:CONTAINS {rkey: item.rkey}]->(u)
+"#;
+
+pub(crate) const ADD_STARTERPACK: &str = r#"
+UNWIND $starterpacks as pack
+MERGE (u:User {did: pack.did})
+MERGE (s:StarterPack {did: pack.did, rkey: pack.rkey})
+SET s.name = pack.name
+MERGE (u)-[:CREATED]->(s)
+WITH s, pack
+OPTIONAL MATCH (s)-[old:USES]->(:List)
+DELETE old
+WITH DISTINCT s, pack
+MERGE (l:List {did: pack.list_did, rkey: pack.list_rkey})
+MERGE (s)-[:USES]->(l)
+"#;
+
+// The pack may well turn up after the people who joined from it
+pub(crate) const ADD_JOINED_VIA: &str = r#"
+UNWIND $joins as joined
+MERGE (u:User {did: joined.did})
+MERGE (s:StarterPack {did: joined.pack_did, rkey: joined.pack_rkey})
+MERGE (u)-[:JOINED_VIA]->(s)
+"#;
+
+// Gates arrive straight after their post, so they only ever need to touch posts we have
+pub(crate) const ADD_THREADGATE: &str = r#"
+UNWIND $threadgates as gate
+MATCH (p:Post {did: gate.did, rkey: gate.rkey})
+SET p.replyRestricted = gate.restricted = "y"
+"#;
+
+pub(crate) const ADD_POSTGATE: &str = r#"
+UNWIND $postgates as gate
+MATCH (p:Post {did: gate.did, rkey: gate.rkey})
+SET p.quotesDisabled = gate.quotes_disabled = "y",
+    p.detachedQuotes = CASE WHEN gate.detached = "" THEN [] ELSE split(gate.detached, ",") END
+WITH p
+OPTIONAL MATCH (q:Post)-[r:QUOTES]->(p)
+WITH p, q, r WHERE r IS NOT NULL
+SET r.detached = "at://" + q.did + "/app.bsky.feed.post/" + q.rkey IN p.detachedQuotes
+"#;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Edits. Edges that come from the record are dropped & rebuilt, everything else stays put
+// (a rebuilt quote picks up detached the same way ADD_QUOTE does)
+pub(crate) const UPDATE_POST: &str = r#"
+UNWIND $post_updates as update
+MATCH (p:Post {did: update.did, rkey: update.rkey})
+SET p.mediaKind = update.media_kind,
+    p.imageCount = toInteger(update.image_count),
+    p.hasAlt = update.has_alt = "y",
+    p.linkDomain = CASE WHEN update.link_domain = "" THEN null ELSE update.link_domain END,
+    p.langs = CASE WHEN update.langs = "" THEN [] ELSE split(update.langs, ",") END
+WITH p, update
+OPTIONAL MATCH (p)-[r:QUOTES|MENTIONS|TAGGED|LINKS]->()
+DELETE r
+WITH DISTINCT p, update
+FOREACH (did IN CASE WHEN update.mentions = "" THEN [] ELSE split(update.mentions, ",") END |
+    MERGE (u:User {did: did})
+    MERGE (p)-[:MENTIONS]->(u))
+FOREACH (tag IN CASE WHEN update.tags = "" THEN [] ELSE split(update.tags, ",") END |
+    MERGE (h:Hashtag {tag: tag})
+    MERGE (p)-[:TAGGED]->(h))
+FOREACH (name IN CASE WHEN update.domains = "" THEN [] ELSE split(update.domains, ",") END |
+    MERGE (d:Domain {name: name})
+    MERGE (p)-[:LINKS]->(d))
+WITH p, update
+OPTIONAL MATCH (q:Post {did: update.quote_did, rkey: update.quote_rkey})
+FOREACH (_ IN CASE WHEN q IS NULL THEN [] ELSE [1] END |
+    MERGE (p)-[r:QUOTES]->(q)
+    SET r.detached = "at://" + p.did + "/app.bsky.feed.post/" + p.rkey IN coalesce(q.detachedQuotes, []))
+"#;
+
+pub(crate) const UPDATE_PROFILE: &str = r#"
+UNWIND $profile_updates as update
+MATCH (u:User {did: update.did})
+SET u.displayName = update.display_name, u.description = update.description
+"#;
+
+pub(crate) const UPDATE_LIST: &str = r#"
+UNWIND $list_updates as update
+MATCH (l:List {did: update.did, rkey: update.rkey})
+SET l.name = update.name, l.purpose = update.purpose, l.description = update.description
 "#;
 
 /////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 pub(crate) const REMOVE_LIKE: &str = r#"
 UNWIND $likes as like
-MATCH (:User {did: like.out})-[r:LIKES {rkey: like.rkey }]->()
+MATCH (:User {did: like.did})-[r:LIKES {rkey: like.rkey }]->()
 DELETE r
 "#;
 
 pub(crate) const REMOVE_FOLLOW: &str = r#"
 UNWIND $follows as follow
-MATCH (:User {did: follow.out})-[r:FOLLOWS {rkey: follow.rkey }]->()
+MATCH (:User {did: follow.did})-[r:FOLLOWS {rkey: follow.rkey }]->()
 DELETE r
 "#;
 
 pub(crate) const REMOVE_BLOCK: &str = r#"
 UNWIND $blocks as block
-MATCH (:User {did: block.out})-[r:BLOCKED  {rkey: block.rkey} ]->()
+MATCH (:User {did: block.did})-[r:BLOCKED  {rkey: block.rkey} ]->()
 DELETE r
 "#;
 
 pub(crate) const REMOVE_POST: &str = r#"
 UNWIND $posts as post
-MATCH (:User {did: post.out})-[r:POSTED {rkey: post.rkey}]->(p:Post)
+MATCH (p:Post {did: post.did, rkey: post.rkey})
 DETACH DELETE p
 "#;
 
+// REPLY_TO & IN_THREAD go with the reply's Post in REMOVE_POST
 pub(crate) const REMOVE_REPLY: &str = r#"
 UNWIND $replies as reply
-MATCH (:User {did: reply.out})-[r:REPLIED_TO {rkey: reply.rkey }]->(p:Post) 
-with p, r
-where p.is_reply == "y"
+MATCH (:User {did: reply.did})-[r:REPLIED_TO {rkey: reply.rkey }]->(:Post)
 DELETE r
 "#;
 
+// Listitems live in the same repo as their list, so the did is the list's too
+pub(crate) const REMOVE_LISTITEM: &str = r#"
+UNWIND $listitems as item
+MATCH (:List {did: item.did})-[r:CONTAINS {rkey: item.rkey}]->()
+DELETE r
+"#;
+
+// The list belongs to the list record, it only goes when that does
+pub(crate) const REMOVE_STARTERPACK: &str = r#"
+UNWIND $starterpacks as pack
+MATCH (s:StarterPack {did: pack.did, rkey: pack.rkey})
+DETACH DELETE s
+"#;
+
+pub(crate) const REMOVE_THREADGATE: &str = r#"
+UNWIND $threadgates as gate
+MATCH (p:Post {did: gate.did, rkey: gate.rkey})
+SET p.replyRestricted = false
+"#;
+
+pub(crate) const REMOVE_POSTGATE: &str = r#"
+UNWIND $postgates as gate
+MATCH (p:Post {did: gate.did, rkey: gate.rkey})
+SET p.quotesDisabled = false, p.detachedQuotes = []
+WITH p
+OPTIONAL MATCH (:Post)-[r:QUOTES]->(p)
+WITH r WHERE r IS NOT NULL
+SET r.detached = false
+"#;
+
+pub(crate) const REMOVE_LIST: &str = r#"
+UNWIND $lists as list
+MATCH (l:List {did: list.did, rkey: list.rkey})
+DETACH DELETE l
+"#;
+
 pub(crate) const REMOVE_REPOST: &str = r#"
 UNWIND $reposts as repost
-MATCH (:User {did: repost.out})-[r:REPOSTED {rkey: repost.rkey }]->()
+MATCH (:User {did: repost.did})-[r:REPOSTED {rkey: repost.rkey }]->()
 DELETE r
 "#;
 
@@ -105,22 +296,238 @@ DETACH DELETE u
 
 /////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+// Backfilled follows come from the AppView, which doesnt tell us the record rkey
+pub(crate) const BACKFILL_FOLLOWS: &str = r#"
+UNWIND $follows as follow
+MERGE (u:User {did: follow.out})
+MERGE (v:User {did: follow.did})
+MERGE (u)-[r:FOLLOWS]->(v)
+"#;
+
+// Without an rkey REMOVE_FOLLOW can never match a backfilled edge, so on a refetch anything
+// backfilled that the AppView no longer lists is an unfollow we missed
+pub(crate) const PRUNE_BACKFILLED_FOLLOWS: &str = r#"
+MATCH (u:User {did: $did})-[r:FOLLOWS]->(v:User)
+WHERE r.rkey IS NULL AND NOT v.did IN $follows
+DELETE r
+"#;
+
+pub(crate) const PRUNE_BACKFILLED_FOLLOWERS: &str = r#"
+MATCH (u:User {did: $did})<-[r:FOLLOWS]-(v:User)
+WHERE r.rkey IS NULL AND NOT v.did IN $followers
+DELETE r
+"#;
+
+pub(crate) const GET_USER_FETCHED: &str = r#"
+MATCH (u:User {did: $did})
+RETURN u.fetched AS fetched
+"#;
+
+pub(crate) const SET_USER_FETCHED: &str = r#"
+MERGE (u:User {did: $did})
+SET u.fetched = $fetched
+"#;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Only users we already know about, identity events come in for the whole network
+pub(crate) const SET_HANDLE: &str = r#"
+UNWIND $handles as handle
+MATCH (u:User {did: handle.did})
+SET u.handle = handle.handle
+"#;
+
+pub(crate) const SET_USER_ACTIVE: &str = r#"
+MATCH (u:User {did: $did})
+SET u.active = $active
+"#;
+
+pub(crate) const PURGE_ACCOUNT: &str = r#"
+MATCH (u:User {did: $did})
+OPTIONAL MATCH (u)-[:POSTED]->(p:Post)
+WITH u, collect(p) AS posts
+FOREACH (p IN posts | DETACH DELETE p)
+DETACH DELETE u
+"#;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) const MIGRATE_POST_DIDS: &str = r#"
+MATCH (u:User)-[:POSTED]->(p:Post)
+WHERE p.did IS NULL
+SET p.did = u.did
+"#;
+
+// FOLLOWS from events used to go (subject)->(follower). Backfilled ones never had an rkey and were
+// always the right way round. Everything is collected before anything is deleted, otherwise a
+// mutual follow would MERGE onto the other edge and then delete it
+pub(crate) const MIGRATE_FOLLOW_DIRECTION: &str = r#"
+MATCH (u:User)-[r:FOLLOWS]->(v:User)
+WHERE r.rkey IS NOT NULL
+WITH collect({src: v, dst: u, rkey: r.rkey}) AS flips, collect(r) AS old
+FOREACH (r IN old | DELETE r)
+WITH flips
+UNWIND flips AS f
+WITH f.src AS src, f.dst AS dst, f.rkey AS rkey
+MERGE (src)-[n:FOLLOWS]->(dst)
+SET n.rkey = rkey
+"#;
+
+// BLOCKED used to go (blockee)->(blocker), and every one of them came from an event
+pub(crate) const MIGRATE_BLOCK_DIRECTION: &str = r#"
+MATCH (u:User)-[r:BLOCKED]->(v:User)
+WITH collect({src: v, dst: u, rkey: r.rkey}) AS flips, collect(r) AS old
+FOREACH (r IN old | DELETE r)
+WITH flips
+UNWIND flips AS f
+WITH f.src AS src, f.dst AS dst, f.rkey AS rkey
+MERGE (src)-[:BLOCKED {rkey: rkey}]->(dst)
+"#;
+
+// Migrations that cant tell by looking whether they already ran leave one of these behind
+pub(crate) const GET_MIGRATION: &str = r#"
+MATCH (m:Migration {id: $id})
+RETURN m.id AS id
+"#;
+
+pub(crate) const SET_MIGRATION: &str = r#"
+MERGE (:Migration {id: $id})
+"#;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) const SET_CURSOR: &str = r#"
+MERGE (c:Cursor {id: $id})
+SET c.time_us = $time_us
+"#;
+
+pub(crate) const GET_CURSOR: &str = r#"
+MATCH (c:Cursor {id: $id})
+RETURN c.time_us AS time_us
+"#;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// One FEEDBACK edge per requester & post, however many times the client tells us about it.
+// signal is -1/1 for show less/more, whichever came last
+pub(crate) const ADD_INTERACTIONS: &str = r#"
+UNWIND $interactions as interaction
+MATCH (p:Post {did: interaction.did, rkey: interaction.rkey})
+MERGE (u:User {did: $did})
+MERGE (u)-[f:FEEDBACK]->(p)
+SET f.timestamp = $now,
+    f.feedContext = CASE WHEN interaction.context = "" THEN f.feedContext ELSE interaction.context END,
+    f.signal = CASE interaction.kind
+        WHEN "less" THEN -1
+        WHEN "more" THEN 1
+        ELSE coalesce(f.signal, 0)
+    END,
+    f.seen = coalesce(f.seen, false) OR interaction.kind = "seen",
+    f.clicked = coalesce(f.clicked, false) OR interaction.kind = "click"
+"#;
+
+// Replies are counted off REPLIED_TO, which goes back further than REPLY_TO
+pub(crate) const GET_POST_ENGAGEMENT: &str = r#"
+UNWIND $posts as post
+MATCH (p:Post {did: post.did, rkey: post.rkey})
+OPTIONAL MATCH (p)<-[l:LIKES]-(:User)
+WITH p, count(l) AS likes
+OPTIONAL MATCH (p)<-[r:REPOSTED]-(:User)
+WITH p, likes, count(r) AS reposts
+OPTIONAL MATCH (p)<-[rp:REPLIED_TO]-(:User)
+WITH p, likes, reposts, count(rp) AS replies
+OPTIONAL MATCH (p)<-[q:QUOTES]-(:Post)
+RETURN p.did AS did, p.rkey AS rkey, likes, reposts, replies, count(q) AS quotes
+"#;
+
+// Net show more/less the requester has given each of `authors`
+pub(crate) const GET_AUTHOR_FEEDBACK: &str = r#"
+MATCH (og:User {did: $did})-[f:FEEDBACK]->(:Post)<-[:POSTED]-(u:User)
+WHERE u.did IN $authors
+RETURN u.did AS did, sum(coalesce(f.signal, 0)) AS signal
+"#;
+
 pub(crate) const GET_FOLLOW_POSTS: &str = r#"
 MATCH (og:User {did: $did})-[:FOLLOWS]->(u:User)-[:POSTED]->(p:Post)
-WITH u,p,og
-OPTIONAL MATCH (og)-[f:FOLLOWS]->(u)
-WITH u, p, CASE WHEN f IS NULL 
-  THEN p ELSE NULL END AS posts
-WHERE posts IS NOT NULL
-RETURN u, posts
+WITH og, u, p, toInteger(p.timestamp) AS timestamp
+WHERE (timestamp < $before_ts OR (timestamp = $before_ts AND p.rkey < $before_rkey))
+  AND NOT (og)-[:FEEDBACK {signal: -1}]->(p)
+  AND coalesce(u.active, true)
+  AND (size($langs) = 0 OR size(coalesce(p.langs, [])) = 0 OR any(l IN p.langs WHERE l IN $langs))
+  AND NOT (p)-[:QUOTES {detached: true}]->()
+  AND NOT (p)-[:QUOTES]->(:Post {quotesDisabled: true})
+RETURN u.did AS did, p.rkey AS rkey, timestamp, toInteger(p.timestamp) AS posted
+ORDER BY timestamp DESC, rkey DESC
+LIMIT $limit
 "#;
 
+// timestamp is what the feed is ordered & paged by, so the cursor still lines up. It has to stay
+// the same between pages, so anything that only changes how good a post is (feedback, reply
+// restricted threads) is left to ranking
 pub(crate) const GET_2ND_DEG_FOLLOW_POSTS: &str = r#"
 MATCH (og:User {did: $did})-[:FOLLOWS]->(:User)-[:FOLLOWS]->(u:User)-[:POSTED]->(p:Post)
-WITH u,p,og
-OPTIONAL MATCH (og)-[f:FOLLOWS]->(u)
-WITH u, p, CASE WHEN f IS NULL 
-  THEN p ELSE NULL END AS posts
-WHERE posts IS NOT NULL
-RETURN u, posts
+WHERE u <> og AND NOT (og)-[:FOLLOWS]->(u)
+WITH DISTINCT og, u, p
+WITH og, u, p, toInteger(p.timestamp) AS timestamp
+WHERE (timestamp < $before_ts OR (timestamp = $before_ts AND p.rkey < $before_rkey))
+  AND NOT (og)-[:FEEDBACK {signal: -1}]->(p)
+  AND coalesce(u.active, true)
+  AND (size($langs) = 0 OR size(coalesce(p.langs, [])) = 0 OR any(l IN p.langs WHERE l IN $langs))
+  AND NOT (p)-[:QUOTES {detached: true}]->()
+  AND NOT (p)-[:QUOTES]->(:Post {quotesDisabled: true})
+RETURN u.did AS did, p.rkey AS rkey, timestamp, toInteger(p.timestamp) AS posted,
+    coalesce(p.replyRestricted, false) AS gated
+ORDER BY timestamp DESC, rkey DESC
+LIMIT $limit
+"#;
+
+// One of the friends' reposts (if any of them reposted it) comes back to show as "reposted by"
+pub(crate) const GET_POPULAR_WITH_FRIENDS_POSTS: &str = r#"
+MATCH (og:User {did: $did})-[:FOLLOWS]->(f:User)-[e:LIKES|REPOSTED]->(p:Post)<-[:POSTED]-(u:User)
+WHERE u <> og AND coalesce(f.active, true) AND NOT (og)-[:FEEDBACK {signal: -1}]->(p)
+WITH u, p, count(DISTINCT f) AS friends,
+    collect(CASE WHEN type(e) = "REPOSTED" THEN {did: f.did, rkey: e.rkey} END) AS reposts,
+    toInteger(p.timestamp) AS timestamp
+WHERE friends >= 2
+  AND coalesce(u.active, true)
+  AND (timestamp < $before_ts OR (timestamp = $before_ts AND p.rkey < $before_rkey))
+  AND (size($langs) = 0 OR size(coalesce(p.langs, [])) = 0 OR any(l IN p.langs WHERE l IN $langs))
+  AND NOT (p)-[:QUOTES {detached: true}]->()
+  AND NOT (p)-[:QUOTES]->(:Post {quotesDisabled: true})
+WITH u, p, friends, head(reposts) AS repost, timestamp
+RETURN u.did AS did, p.rkey AS rkey, timestamp, toInteger(p.timestamp) AS posted,
+    coalesce(p.replyRestricted, false) AS gated,
+    repost.did AS repost_did, repost.rkey AS repost_rkey,
+    "friends=" + toString(friends) AS context
+ORDER BY timestamp DESC, rkey DESC
+LIMIT $limit
+"#;
+
+pub(crate) const GET_LIST_POSTS: &str = r#"
+MATCH (:List {did: $list_did, rkey: $list_rkey})-[:CONTAINS]->(u:User)-[:POSTED]->(p:Post)
+WITH DISTINCT u, p, toInteger(p.timestamp) AS timestamp
+WHERE (timestamp < $before_ts OR (timestamp = $before_ts AND p.rkey < $before_rkey))
+  AND coalesce(u.active, true)
+  AND (size($langs) = 0 OR size(coalesce(p.langs, [])) = 0 OR any(l IN p.langs WHERE l IN $langs))
+  AND NOT (p)-[:QUOTES {detached: true}]->()
+  AND NOT (p)-[:QUOTES]->(:Post {quotesDisabled: true})
+RETURN u.did AS did, p.rkey AS rkey, timestamp, toInteger(p.timestamp) AS posted
+ORDER BY timestamp DESC, rkey DESC
+LIMIT $limit
+"#;
+
+// Posts from the members of whichever starter pack the requester signed up through
+pub(crate) const GET_STARTER_PACK_POSTS: &str = r#"
+MATCH (og:User {did: $did})-[:JOINED_VIA]->(:StarterPack)-[:USES]->(:List)-[:CONTAINS]->(u:User)-[:POSTED]->(p:Post)
+WHERE u <> og
+WITH DISTINCT og, u, p, toInteger(p.timestamp) AS timestamp
+WHERE (timestamp < $before_ts OR (timestamp = $before_ts AND p.rkey < $before_rkey))
+  AND NOT (og)-[:FEEDBACK {signal: -1}]->(p)
+  AND coalesce(u.active, true)
+  AND (size($langs) = 0 OR size(coalesce(p.langs, [])) = 0 OR any(l IN p.langs WHERE l IN $langs))
+  AND NOT (p)-[:QUOTES {detached: true}]->()
+  AND NOT (p)-[:QUOTES]->(:Post {quotesDisabled: true})
+RETURN u.did AS did, p.rkey AS rkey, timestamp, toInteger(p.timestamp) AS posted
+ORDER BY timestamp DESC, rkey DESC
+LIMIT $limit
 "#;
@@ -1,10 +1,12 @@
+use bsky::{ErrorCounts, IngestError};
 use common::FetchMessage;
 use futures_util::StreamExt;
-use graph::GraphModel;
+use graph::{GraphModel, QueueConfig};
 use pprof::protos::Message;
+use std::time::{Duration, Instant};
 use std::{env, process};
 use std::{fs::File, io::Write, thread};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio_tungstenite::connect_async;
 
 pub mod bsky;
@@ -12,93 +14,210 @@ pub mod common;
 pub mod graph;
 mod server;
 
-const URL: &str = "wss://jetstream1.us-east.bsky.network/subscribe?wantedCollections=app.bsky.graph.*&wantedCollections=app.bsky.feed.*";
+const URL: &str = "wss://jetstream1.us-east.bsky.network/subscribe?wantedCollections=app.bsky.graph.*&wantedCollections=app.bsky.feed.*&wantedCollections=app.bsky.actor.profile";
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+const CURSOR_REWIND_US: i64 = 3_000_000;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let profile = env::var("PROFILE_ENABLE").unwrap_or("".into());
     let compression = env::var("COMPRESS_ENABLE").unwrap_or("".into());
-    let compress;
-    if !compression.is_empty() {
+    let compress = !compression.is_empty();
+    if compress {
         println!("Compression enabled");
-        compress = true;
-    } else {
-        compress = false;
     }
-    if !profile.is_empty() {
-        let guard = pprof::ProfilerGuardBuilder::default()
-            .frequency(1000)
-            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-            .build()
-            .unwrap();
-
-        ctrlc::set_handler(move || {
-            println!("Shutting down");
-            match guard.report().build() {
-                Ok(report) => {
-                    let mut file = File::create("profile.pb").unwrap();
-                    let profile = report.pprof().unwrap();
+    let guard = if !profile.is_empty() {
+        Some(
+            pprof::ProfilerGuardBuilder::default()
+                .frequency(1000)
+                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+                .build()
+                .unwrap(),
+        )
+    } else {
+        None
+    };
 
-                    let mut content = Vec::new();
-                    profile.write_to_vec(&mut content).unwrap();
-                    file.write_all(&content).unwrap();
-                }
-                Err(_) => {}
-            };
-            //TODO Exit properly
+    // Everything watches this, ingest stops reading & drains, the web server stops accepting
+    let (shutdown_send, mut shutdown) = watch::channel(false);
+    ctrlc::set_handler(move || {
+        if *shutdown_send.borrow() {
+            println!("Forcing shutdown");
             process::exit(0x0100);
-        })
-        .expect("Error setting Ctrl-C handler");
-    }
+        }
+        println!("Shutting down, ctrl-c again to force");
+        let _ = shutdown_send.send(true);
+    })
+    .expect("Error setting Ctrl-C handler");
 
     let (send, recv) = mpsc::channel::<FetchMessage>(100);
-    let mut graph = GraphModel::new("bolt://localhost:7687", "user", "pass", recv)
+    let queue_config = QueueConfig::from_env();
+    println!("Write queues: {:?}", queue_config);
+    let mut graph = GraphModel::new("bolt://localhost:7687", "user", "pass", recv, queue_config)
         .await
         .unwrap();
     let server_conn = graph.inner();
-    thread::spawn(move || {
+    let server_write_lock = graph.write_lock();
+    let resolver = server::did::resolver_from_env()?;
+    let server_shutdown = shutdown.clone();
+    let web_thread = thread::spawn(move || {
         let web_runtime: tokio::runtime::Runtime = tokio::runtime::Runtime::new().unwrap();
         println!("Starting web listener thread");
         let wait = web_runtime.spawn(async move {
-            server::serve(send, server_conn).await.unwrap();
+            server::serve(
+                send,
+                server_conn,
+                server_write_lock,
+                resolver,
+                server_shutdown,
+            )
+            .await
+            .unwrap();
         });
         web_runtime.block_on(wait).unwrap();
         println!("Exiting web listener thread");
     });
 
-    //
-
-    // Connect to the websocket
-    let url;
-    if compress {
-        url = format!("wss://jetstream1.us-east.bsky.network/subscribe?wantedCollections=app.bsky.graph.*&wantedCollections=app.bsky.feed.*&compress={}", "true");
-    } else {
-        url =  format!("wss://jetstream1.us-east.bsky.network/subscribe?wantedCollections=app.bsky.graph.*&wantedCollections=app.bsky.feed.*&compress={}", "false");
+    // Pick up from wherever we last checkpointed, so restarts dont leave holes in the graph
+    let mut cursor = graph.load_cursor().await?;
+    match cursor {
+        Some(c) => println!("Resuming from cursor {c}"),
+        None => println!("No cursor found, starting from live"),
     }
-    let (ws_stream, _) = connect_async(url).await?;
-    println!("Connected to Bluesky firehose");
-    // Split the websocket into sender and receiver
-    let (_, mut read) = ws_stream.split();
-
-    while let Some(message) = read.next().await {
-        match Some(message) {
-            Some(m) => {
-                match bsky::handle_event(m, &mut graph, compress).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!("Error handling event: {}", e);
-                        let (stream, _) = connect_async(URL).await?;
-                        read = stream.split().1;
+    let mut catching_up = cursor.is_some();
+    let mut last_checkpoint = Instant::now();
+    let mut errors = ErrorCounts::default();
+    // Quiet queues would otherwise only get written when an event happens to fill them up
+    let mut flush_ticker = tokio::time::interval(graph.flush_tick());
+    flush_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    'ingest: loop {
+        // Connect to the websocket
+        let url = jetstream_url(compress, cursor);
+        let connected = tokio::select! {
+            c = connect_async(url) => c,
+            _ = shutdown.wait_for(|s| *s) => break 'ingest,
+        };
+        let ws_stream = match connected {
+            Ok((s, _)) => s,
+            Err(e) => {
+                println!("Error connecting to Bluesky firehose: {}", e);
+                tokio::select! {
+                    _ = tokio::time::sleep(RECONNECT_DELAY) => continue,
+                    _ = shutdown.wait_for(|s| *s) => break 'ingest,
+                }
+            }
+        };
+        println!("Connected to Bluesky firehose");
+        // Split the websocket into sender and receiver
+        let (_, mut read) = ws_stream.split();
+
+        loop {
+            let message = tokio::select! {
+                m = read.next() => match m {
+                    Some(m) => m,
+                    None => break,
+                },
+                _ = flush_ticker.tick() => {
+                    graph.flush_stale().await?;
+                    continue;
+                }
+                // Whatever we've already read still goes in, anything after it is left for the replay
+                _ = shutdown.wait_for(|s| *s) => break 'ingest,
+            };
+
+            match bsky::handle_event(message, &mut graph, compress, catching_up).await {
+                Ok(Some(time_us)) => {
+                    cursor = Some(time_us);
+                    if catching_up && bsky::drift_ms(time_us) < bsky::MAX_DRIFT_MS {
+                        println!("Caught up with firehose");
+                        catching_up = false;
                     }
-                };
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    errors.record(&e);
+                    match e {
+                        // One bad frame shouldnt cost us the rest of the stream
+                        IngestError::Decompress(_)
+                        | IngestError::Decode(_)
+                        | IngestError::Schema(_) => {
+                            println!("Skipping event: {}", e);
+                        }
+                        // Start again from the last event we handled, the replay will catch us up
+                        IngestError::Connection(_) | IngestError::Lag(_) => {
+                            println!("Error handling event: {}", e);
+                            break;
+                        }
+                        // Nowhere to put anything, let whatever's supervising us restart from the checkpoint
+                        IngestError::Storage(_) => {
+                            println!("Error handling event, aborting: {}", e);
+                            return Err(e.into());
+                        }
+                    }
+                }
+            };
+
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                if let Some(c) = cursor {
+                    graph.flush_all().await?;
+                    graph.save_cursor(c).await?;
+                }
+                if errors.total() > 0 {
+                    println!("Ingest errors so far - {}", errors);
+                }
+                last_checkpoint = Instant::now();
             }
-            None => {}
+        }
+
+        // Anything from before the cursor is still in the queues, so only replay from there on
+        println!("Reconnecting to Bluesky firehose");
+        catching_up = cursor.is_some();
+    }
+
+    println!("Flushing write queues");
+    graph.flush_all().await?;
+    if let Some(c) = cursor {
+        graph.save_cursor(c).await?;
+        println!("Saved cursor {c}");
+    }
+
+    // axum lets in-flight requests finish before serve returns
+    match tokio::task::spawn_blocking(move || web_thread.join()).await {
+        Ok(Ok(_)) => {}
+        _ => println!("Web listener thread panicked"),
+    }
+
+    if let Some(guard) = guard {
+        if let Err(e) = write_profile(&guard) {
+            println!("Error writing profile: {}", e);
         }
     }
+    println!("Shut down cleanly");
+    Ok(())
+}
+
+fn write_profile(guard: &pprof::ProfilerGuard) -> Result<(), Box<dyn std::error::Error>> {
+    let report = guard.report().build()?;
+    let profile = report.pprof()?;
 
+    let mut content = Vec::new();
+    profile.write_to_vec(&mut content)?;
+    File::create("profile.pb")?.write_all(&content)?;
+    println!("Wrote profile.pb");
     Ok(())
 }
 
+fn jetstream_url(compress: bool, cursor: Option<i64>) -> String {
+    let mut url = format!("{URL}&compress={compress}");
+    if let Some(c) = cursor {
+        // Rewind a little; writes are idempotent so overlap is harmless, gaps are not
+        url = format!("{url}&cursor={}", c - CURSOR_REWIND_US);
+    }
+    url
+}
+
 //Todo:
 /*
 - Forward port 80 & 443 to this ip (connect over wifi)
@@ -112,13 +231,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 
 For impl:
-- Store languages
-- Implement messaging logic to request fetches for first user case
-    - Match(u:User) return U; if u.fetched == null, fetch followers, follows & write back to DB
-        - Worth creating accounts if they dont exist even with 0 posts, because otherwise it wont be recorded
 - For a start try returning 2nd degree posts to test latency
 - Add read replica
-- Have some kind of cursoring; either pre-fetching a load and trickle-feeding it back, or take note of the oldest post returned & use that in query
-(e.g if we LIMIT 50, then the next query will match only for p.timestamp < cursor'd timestamp)
 - Tweak algo+++++
 */
@@ -1,45 +1,359 @@
+use axum::http::StatusCode;
 use base64::{engine::general_purpose, Engine as _};
 use serde_derive::{Deserialize, Serialize};
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
-pub fn verify_jwt(jwtstr: &str, service_did: &String) -> Result<String, String> {
-    let parts = jwtstr.split(".").map(String::from).collect::<Vec<_>>();
+
+use super::did::{DidResolver, KeyType, SigningKey};
+use super::types::XrpcError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Malformed(String),
+    Expired,
+    WrongAudience,
+    WrongMethod,
+    UnsupportedAlg(String),
+    BadSignature,
+    Resolve(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "authentication required"),
+            AuthError::Malformed(e) => write!(f, "poorly formatted jwt: {e}"),
+            AuthError::Expired => write!(f, "jwt expired"),
+            AuthError::WrongAudience => write!(f, "jwt audience does not match service did"),
+            AuthError::WrongMethod => write!(f, "jwt lexicon method does not match endpoint"),
+            AuthError::UnsupportedAlg(a) => write!(f, "unsupported jwt alg {a}"),
+            AuthError::BadSignature => write!(f, "jwt signature does not match issuer key"),
+            AuthError::Resolve(e) => write!(f, "could not resolve issuer key: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<AuthError> for XrpcError {
+    fn from(e: AuthError) -> Self {
+        let error = match e {
+            AuthError::Missing => "AuthMissing",
+            AuthError::Expired => "JwtExpired",
+            AuthError::WrongAudience => "BadJwtAudience",
+            AuthError::WrongMethod => "BadJwtLexiconMethod",
+            AuthError::BadSignature | AuthError::Resolve(_) => "BadJwtSignature",
+            AuthError::Malformed(_) | AuthError::UnsupportedAlg(_) => "BadJwt",
+        };
+        XrpcError::new(StatusCode::UNAUTHORIZED, error, e.to_string())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub alg: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Jwt {
+    pub iss: String,
+    pub aud: String,
+    pub exp: u128,
+    // Optional as far as parsing goes, so leaving it out is a WrongMethod rather than Malformed
+    pub lxm: Option<String>,
+}
+
+// Returns the requester's DID (the iss claim) once the token is known to be theirs,
+// for us, meant for this endpoint and still valid
+pub async fn verify_jwt(
+    jwtstr: &str,
+    service_did: &str,
+    nsid: &str,
+    resolver: &dyn DidResolver,
+) -> Result<String, AuthError> {
+    let parts = jwtstr.split(".").collect::<Vec<_>>();
 
     if parts.len() != 3 {
-        return Err("poorly formatted jwt".into());
+        return Err(AuthError::Malformed("expected 3 parts".into()));
     }
 
-    let bytes = general_purpose::STANDARD_NO_PAD.decode(&parts[1]).unwrap();
+    let header: Header = decode_part(parts[0])?;
+    let payload: Jwt = decode_part(parts[1])?;
+    let sig = match general_purpose::URL_SAFE_NO_PAD.decode(parts[2]) {
+        Ok(s) => s,
+        Err(_) => return Err(AuthError::Malformed("signature is not base64url".into())),
+    };
+
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    if since_the_epoch.as_millis() / 1000 > payload.exp {
+        return Err(AuthError::Expired);
+    }
+    if service_did != payload.aud {
+        return Err(AuthError::WrongAudience);
+    }
+    // A token without lxm would be good for any endpoint, so it doesnt get to be good for ours
+    if payload.lxm.as_deref() != Some(nsid) {
+        return Err(AuthError::WrongMethod);
+    }
+
+    // Labelers sign with a service-specific key, but the iss is still rooted at their DID
+    let did = payload.iss.split('#').next().unwrap_or_default();
+    let signed = &jwtstr[..parts[0].len() + 1 + parts[1].len()];
+
+    let key = resolver.resolve(did, false).await?.signing_key()?;
+    if verify_sig(&header.alg, &key, signed.as_bytes(), &sig).is_ok() {
+        return Ok(did.to_owned());
+    }
+
+    // The key may have been rotated since we cached it, so give it one more go
+    let key = resolver.resolve(did, true).await?.signing_key()?;
+    verify_sig(&header.alg, &key, signed.as_bytes(), &sig)?;
+    Ok(did.to_owned())
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, AuthError> {
+    let bytes = match general_purpose::URL_SAFE_NO_PAD.decode(part) {
+        Ok(b) => b,
+        Err(_) => return Err(AuthError::Malformed("not base64url".into())),
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(p) => Ok(p),
+        Err(e) => Err(AuthError::Malformed(e.to_string())),
+    }
+}
+
+fn verify_sig(alg: &str, key: &SigningKey, msg: &[u8], sig: &[u8]) -> Result<(), AuthError> {
+    use k256::ecdsa::signature::Verifier;
+
+    match (alg, key.key_type) {
+        ("ES256K", KeyType::K256) => {
+            let vk = k256::ecdsa::VerifyingKey::from_sec1_bytes(&key.bytes)
+                .map_err(|_| AuthError::Resolve("malformed secp256k1 key".into()))?;
+            let sig =
+                k256::ecdsa::Signature::from_slice(sig).map_err(|_| AuthError::BadSignature)?;
+            vk.verify(msg, &sig).map_err(|_| AuthError::BadSignature)
+        }
+        ("ES256", KeyType::P256) => {
+            let vk = p256::ecdsa::VerifyingKey::from_sec1_bytes(&key.bytes)
+                .map_err(|_| AuthError::Resolve("malformed p256 key".into()))?;
+            let sig =
+                p256::ecdsa::Signature::from_slice(sig).map_err(|_| AuthError::BadSignature)?;
+            vk.verify(msg, &sig).map_err(|_| AuthError::BadSignature)
+        }
+        ("ES256K", _) | ("ES256", _) => Err(AuthError::BadSignature),
+        (a, _) => Err(AuthError::UnsupportedAlg(a.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::did::{DidDocument, StaticResolver, VerificationMethod};
+    use async_trait::async_trait;
+
+    const DID: &str = "did:plc:testuser";
+    const SERVICE_DID: &str = "did:web:feeds.example.com";
+    const NSID: &str = "app.bsky.feed.getFeedSkeleton";
+
+    enum Key {
+        K256(k256::ecdsa::SigningKey),
+        P256(p256::ecdsa::SigningKey),
+    }
+
+    impl Key {
+        fn k256(seed: u8) -> Self {
+            Key::K256(k256::ecdsa::SigningKey::from_slice(&[seed; 32]).unwrap())
+        }
+
+        fn p256(seed: u8) -> Self {
+            Key::P256(p256::ecdsa::SigningKey::from_slice(&[seed; 32]).unwrap())
+        }
 
-    if let Ok(payload) = std::str::from_utf8(&bytes) {
-        if let Ok(payload) = serde_json::from_str::<JWT>(payload) {
-            let start = SystemTime::now();
-            let since_the_epoch = start
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards");
+        fn alg(&self) -> &str {
+            match self {
+                Key::K256(_) => "ES256K",
+                Key::P256(_) => "ES256",
+            }
+        }
 
-            if since_the_epoch.as_millis() / 1000 > payload.exp {
-                return Err("jwt expired".into());
+        fn sign(&self, msg: &[u8]) -> Vec<u8> {
+            use k256::ecdsa::signature::Signer;
+            match self {
+                Key::K256(k) => {
+                    let sig: k256::ecdsa::Signature = k.sign(msg);
+                    sig.to_bytes().to_vec()
+                }
+                Key::P256(k) => {
+                    let sig: p256::ecdsa::Signature = k.sign(msg);
+                    sig.to_bytes().to_vec()
+                }
             }
-            if service_did != &payload.aud {
-                return Err("jwt audience does not match service did".into());
+        }
+
+        // Multikey is the multicodec prefix then the compressed key, base58btc
+        fn doc(&self) -> DidDocument {
+            let (prefix, point) = match self {
+                Key::K256(k) => ([0xe7, 0x01], k.verifying_key().to_encoded_point(true)),
+                Key::P256(k) => ([0x80, 0x24], k.verifying_key().to_encoded_point(true)),
+            };
+            let bytes = [&prefix[..], point.as_bytes()].concat();
+            DidDocument {
+                id: DID.to_owned(),
+                verification_method: vec![VerificationMethod {
+                    id: "#atproto".to_owned(),
+                    type_field: "Multikey".to_owned(),
+                    public_key_multibase: Some(format!("z{}", bs58::encode(bytes).into_string())),
+                }],
             }
-            if let Ok(jwtstr) = serde_json::to_string(&payload) {
-                println!("you need to actually do something with this btw {jwtstr}");
-                Ok(jwtstr)
-            } else {
-                Err("error parsing payload".into())
+        }
+    }
+
+    fn b64(bytes: &[u8]) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn now() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u128
+    }
+
+    fn claims(aud: &str, exp: u128, lxm: &str) -> Jwt {
+        Jwt {
+            iss: DID.to_owned(),
+            aud: aud.to_owned(),
+            exp,
+            lxm: Some(lxm.to_owned()),
+        }
+    }
+
+    fn token(key: &Key, payload: &Jwt) -> String {
+        let header = b64(&serde_json::to_vec(&Header {
+            alg: key.alg().to_owned(),
+        })
+        .unwrap());
+        let payload = b64(&serde_json::to_vec(payload).unwrap());
+        let signed = format!("{header}.{payload}");
+        let sig = b64(&key.sign(signed.as_bytes()));
+        format!("{signed}.{sig}")
+    }
+
+    fn valid() -> Jwt {
+        claims(SERVICE_DID, now() + 60, NSID)
+    }
+
+    async fn verify(token: &str, key: &Key) -> Result<String, AuthError> {
+        let resolver = StaticResolver::new(vec![key.doc()]);
+        verify_jwt(token, SERVICE_DID, NSID, &resolver).await
+    }
+
+    #[tokio::test]
+    async fn accepts_es256k() {
+        let key = Key::k256(1);
+        assert_eq!(
+            verify(&token(&key, &valid()), &key).await,
+            Ok(DID.to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_es256() {
+        let key = Key::p256(2);
+        assert_eq!(
+            verify(&token(&key, &valid()), &key).await,
+            Ok(DID.to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_payload() {
+        let key = Key::k256(1);
+        let jwt = token(&key, &valid());
+        let parts = jwt.split('.').collect::<Vec<_>>();
+        let mut payload = valid();
+        payload.iss = "did:plc:someoneelse".to_owned();
+        let forged = b64(&serde_json::to_vec(&payload).unwrap());
+        let tampered = format!("{}.{}.{}", parts[0], forged, parts[2]);
+
+        // Resolves the forged iss to the real key, so only the signature can catch it
+        let mut doc = key.doc();
+        doc.id = payload.iss.clone();
+        let resolver = StaticResolver::new(vec![doc]);
+        assert_eq!(
+            verify_jwt(&tampered, SERVICE_DID, NSID, &resolver).await,
+            Err(AuthError::BadSignature)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_audience() {
+        let key = Key::k256(1);
+        let jwt = token(&key, &claims("did:web:other.example.com", now() + 60, NSID));
+        assert_eq!(verify(&jwt, &key).await, Err(AuthError::WrongAudience));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_method() {
+        let key = Key::k256(1);
+        let jwt = token(
+            &key,
+            &claims(SERVICE_DID, now() + 60, "app.bsky.feed.sendInteractions"),
+        );
+        assert_eq!(verify(&jwt, &key).await, Err(AuthError::WrongMethod));
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_method() {
+        let key = Key::k256(1);
+        let jwt = token(
+            &key,
+            &Jwt {
+                lxm: None,
+                ..valid()
+            },
+        );
+        assert_eq!(verify(&jwt, &key).await, Err(AuthError::WrongMethod));
+    }
+
+    #[tokio::test]
+    async fn rejects_expired() {
+        let key = Key::k256(1);
+        let jwt = token(&key, &claims(SERVICE_DID, now() - 60, NSID));
+        assert_eq!(verify(&jwt, &key).await, Err(AuthError::Expired));
+    }
+
+    // Hands out the old document until asked for a fresh one
+    struct RotatedResolver {
+        stale: StaticResolver,
+        current: StaticResolver,
+    }
+
+    #[async_trait]
+    impl DidResolver for RotatedResolver {
+        async fn resolve(&self, did: &str, fresh: bool) -> Result<DidDocument, AuthError> {
+            match fresh {
+                true => self.current.resolve(did, fresh).await,
+                false => self.stale.resolve(did, fresh).await,
             }
-        } else {
-            Err("error parsing payload".into())
         }
-    } else {
-        Err("error parsing payload".into())
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct JWT {
-    pub iss: String,
-    pub aud: String,
-    pub exp: u128,
+    #[tokio::test]
+    async fn accepts_rotated_key_after_fresh_resolve() {
+        let old = Key::k256(1);
+        let new = Key::k256(3);
+        let resolver = RotatedResolver {
+            stale: StaticResolver::new(vec![old.doc()]),
+            current: StaticResolver::new(vec![new.doc()]),
+        };
+        let jwt = token(&new, &valid());
+        assert_eq!(
+            verify_jwt(&jwt, SERVICE_DID, NSID, &resolver).await,
+            Ok(DID.to_owned())
+        );
+    }
 }
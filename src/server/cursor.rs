@@ -0,0 +1,84 @@
+use base64::{engine::general_purpose, Engine as _};
+
+// Position of the last post on a page. Posts are ordered newest first by timestamp,
+// with the rkey breaking ties so two posts in the same microsecond still page cleanly
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub timestamp: i64,
+    pub rkey: String,
+}
+
+impl Cursor {
+    // Clients should treat this as opaque, so dont leak the layout
+    pub fn encode(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(format!("{}::{}", self.timestamp, self.rkey))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, String> {
+        let bytes = match general_purpose::URL_SAFE_NO_PAD.decode(cursor) {
+            Ok(b) => b,
+            Err(_) => return Err("malformed cursor".into()),
+        };
+        let raw = match String::from_utf8(bytes) {
+            Ok(r) => r,
+            Err(_) => return Err("malformed cursor".into()),
+        };
+        match raw.split_once("::") {
+            Some((timestamp, rkey)) if !rkey.is_empty() => match timestamp.parse::<i64>() {
+                Ok(timestamp) => Ok(Self {
+                    timestamp,
+                    rkey: rkey.to_owned(),
+                }),
+                Err(_) => Err("malformed cursor".into()),
+            },
+            _ => Err("malformed cursor".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for rkey in ["3kabc2xyz", "a:b", "a::b", "::"] {
+            let cursor = Cursor {
+                timestamp: 1_700_000_000_000_000,
+                rkey: rkey.to_owned(),
+            };
+            assert_eq!(Cursor::decode(&cursor.encode()), Ok(cursor));
+        }
+    }
+
+    #[test]
+    fn negative_timestamp_round_trips() {
+        let cursor = Cursor {
+            timestamp: -5,
+            rkey: "abc".to_owned(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Ok(cursor));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let encode = |s: &str| general_purpose::URL_SAFE_NO_PAD.encode(s);
+        let bad = [
+            String::new(),
+            "!!!".to_owned(),
+            "not base64 at all".to_owned(),
+            general_purpose::URL_SAFE_NO_PAD.encode([0xff, 0xfe, 0xfd]),
+            encode("12345"),
+            encode("12345::"),
+            encode("::abc"),
+            encode("abc::def"),
+            encode("99999999999999999999::abc"),
+        ];
+        for cursor in bad {
+            assert!(
+                Cursor::decode(&cursor).is_err(),
+                "{cursor} should not decode"
+            );
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use super::auth::AuthError;
+
+const DEFAULT_PLC_URL: &str = "https://plc.directory";
+const DOC_TTL: Duration = Duration::from_secs(60 * 60);
+
+// multicodec prefixes for compressed public keys in a Multikey
+const SECP256K1_PREFIX: [u8; 2] = [0xe7, 0x01];
+const P256_PREFIX: [u8; 2] = [0x80, 0x24];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    K256,
+    P256,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningKey {
+    pub key_type: KeyType,
+    // compressed SEC1 bytes
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidDocument {
+    pub id: String,
+    #[serde(default)]
+    pub verification_method: Vec<VerificationMethod>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationMethod {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub type_field: String,
+    pub public_key_multibase: Option<String>,
+}
+
+impl DidDocument {
+    // The key repos and service auth tokens are signed with lives under #atproto
+    pub fn signing_key(&self) -> Result<SigningKey, AuthError> {
+        let method = self
+            .verification_method
+            .iter()
+            .find(|m| m.id == "#atproto" || m.id == format!("{}#atproto", self.id))
+            .ok_or_else(|| AuthError::Resolve(format!("no atproto key for {}", self.id)))?;
+
+        let multibase = match &method.public_key_multibase {
+            Some(m) => m,
+            None => return Err(AuthError::Resolve("key is not multibase".into())),
+        };
+        // z is base58btc, the only encoding used here
+        let encoded = match multibase.strip_prefix('z') {
+            Some(e) => e,
+            None => return Err(AuthError::Resolve("unsupported multibase encoding".into())),
+        };
+        let decoded = match bs58::decode(encoded).into_vec() {
+            Ok(d) => d,
+            Err(_) => return Err(AuthError::Resolve("malformed multibase key".into())),
+        };
+
+        match method.type_field.as_str() {
+            "Multikey" => {
+                if decoded.starts_with(&SECP256K1_PREFIX) {
+                    Ok(SigningKey {
+                        key_type: KeyType::K256,
+                        bytes: decoded[2..].to_vec(),
+                    })
+                } else if decoded.starts_with(&P256_PREFIX) {
+                    Ok(SigningKey {
+                        key_type: KeyType::P256,
+                        bytes: decoded[2..].to_vec(),
+                    })
+                } else {
+                    Err(AuthError::Resolve("unsupported key type".into()))
+                }
+            }
+            // Older documents carry the raw key, with the curve given by the method type
+            "EcdsaSecp256k1VerificationKey2019" => Ok(SigningKey {
+                key_type: KeyType::K256,
+                bytes: decoded,
+            }),
+            "EcdsaSecp256r1VerificationKey2019" => Ok(SigningKey {
+                key_type: KeyType::P256,
+                bytes: decoded,
+            }),
+            t => Err(AuthError::Resolve(format!(
+                "unsupported verification method {t}"
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+pub trait DidResolver: Send + Sync {
+    // `fresh` skips any cache, for when a key looks like it has been rotated
+    async fn resolve(&self, did: &str, fresh: bool) -> Result<DidDocument, AuthError>;
+}
+
+// Resolves did:plc against a PLC directory and did:web over HTTPS
+pub struct NetworkResolver {
+    plc_url: String,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<String, (Instant, DidDocument)>>,
+}
+
+impl NetworkResolver {
+    pub fn new(plc_url: &str) -> Self {
+        Self {
+            plc_url: plc_url.trim_end_matches('/').to_owned(),
+            client: reqwest::Client::new(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn doc_url(&self, did: &str) -> Result<String, AuthError> {
+        if did.starts_with("did:plc:") {
+            Ok(format!("{}/{}", self.plc_url, did))
+        } else if let Some(host) = did.strip_prefix("did:web:") {
+            // atproto only allows hostnames here, with an optional percent-encoded port
+            if host.contains(':') || host.contains('/') {
+                return Err(AuthError::Resolve(format!("unsupported did:web {did}")));
+            }
+            Ok(format!(
+                "https://{}/.well-known/did.json",
+                host.replace("%3A", ":")
+            ))
+        } else {
+            Err(AuthError::Resolve(format!("unsupported did method {did}")))
+        }
+    }
+}
+
+#[async_trait]
+impl DidResolver for NetworkResolver {
+    async fn resolve(&self, did: &str, fresh: bool) -> Result<DidDocument, AuthError> {
+        if !fresh {
+            if let Some((fetched, doc)) = self.cache.lock().await.get(did) {
+                if fetched.elapsed() < DOC_TTL {
+                    return Ok(doc.clone());
+                }
+            }
+        }
+
+        let url = self.doc_url(did)?;
+        let resp = match self.client.get(&url).send().await {
+            Ok(r) => r,
+            Err(e) => return Err(AuthError::Resolve(format!("error fetching {url}: {e}"))),
+        };
+        if !resp.status().is_success() {
+            return Err(AuthError::Resolve(format!(
+                "error fetching {url}: {}",
+                resp.status()
+            )));
+        }
+        let doc: DidDocument = match resp.json().await {
+            Ok(d) => d,
+            Err(e) => return Err(AuthError::Resolve(format!("bad did document {url}: {e}"))),
+        };
+        if doc.id != did {
+            return Err(AuthError::Resolve(format!(
+                "did document for {url} is not {did}"
+            )));
+        }
+
+        self.cache
+            .lock()
+            .await
+            .insert(did.to_owned(), (Instant::now(), doc.clone()));
+        Ok(doc)
+    }
+}
+
+// Stand-in for local runs and tests, serves documents from memory instead of the network
+pub struct StaticResolver {
+    docs: HashMap<String, DidDocument>,
+}
+
+impl StaticResolver {
+    pub fn new(docs: Vec<DidDocument>) -> Self {
+        Self {
+            docs: docs.into_iter().map(|d| (d.id.clone(), d)).collect(),
+        }
+    }
+
+    // File is a JSON array of DID documents
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let docs: Vec<DidDocument> = serde_json::from_slice(&fs::read(path)?)?;
+        Ok(Self::new(docs))
+    }
+}
+
+#[async_trait]
+impl DidResolver for StaticResolver {
+    async fn resolve(&self, did: &str, _fresh: bool) -> Result<DidDocument, AuthError> {
+        match self.docs.get(did) {
+            Some(d) => Ok(d.clone()),
+            None => Err(AuthError::Resolve(format!("unknown did {did}"))),
+        }
+    }
+}
+
+// LOCAL_DID_DOCS points at a file of documents to use instead of the network
+pub fn resolver_from_env() -> Result<Arc<dyn DidResolver>, Box<dyn std::error::Error>> {
+    match env::var("LOCAL_DID_DOCS") {
+        Ok(path) => {
+            println!("Resolving DIDs from {path}");
+            Ok(Arc::new(StaticResolver::from_file(&path)?))
+        }
+        Err(_) => {
+            let plc_url = env::var("PLC_DIRECTORY_URL").unwrap_or(DEFAULT_PLC_URL.into());
+            Ok(Arc::new(NetworkResolver::new(&plc_url)))
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use neo4rs::Graph;
+use std::{collections::HashMap, env};
+
+use crate::common::{lang, AtUri};
+use crate::graph::{self, queries, FeedPost};
+
+pub const FOLLOWING_RKEY: &str = "m1k_test_feed";
+pub const FRIENDS_OF_FRIENDS_RKEY: &str = "friends-of-friends";
+pub const POPULAR_WITH_FRIENDS_RKEY: &str = "popular-with-friends";
+pub const STARTER_PACK_RKEY: &str = "starter-pack";
+
+// Everything a feed gets to know about the request it is answering
+#[derive(Debug, Clone)]
+pub struct FeedRequest {
+    pub requester: String,
+    pub limit: i64,
+    // (timestamp, rkey) of the last post already served
+    pub before: Option<(i64, String)>,
+    // From the requester's Accept-Language, empty if they didnt say
+    pub langs: Vec<String>,
+}
+
+#[async_trait]
+pub trait FeedAlgorithm: Send + Sync {
+    // Record key of the app.bsky.feed.generator record this feed is published under
+    fn rkey(&self) -> &str;
+
+    async fn posts(&self, conn: &Graph, req: &FeedRequest) -> Result<Vec<FeedPost>, neo4rs::Error>;
+}
+
+// A feed that is just one of the graph feed queries run for the requester
+pub struct QueryFeed {
+    rkey: String,
+    query: &'static str,
+    // Languages this feed is pinned to, whatever the requester asks for. Empty to go by the request
+    langs: Vec<String>,
+}
+
+impl QueryFeed {
+    pub fn new(rkey: &str, query: &'static str) -> Self {
+        Self {
+            rkey: rkey.to_owned(),
+            query,
+            langs: Vec::new(),
+        }
+    }
+
+    pub fn with_langs(mut self, langs: Vec<String>) -> Self {
+        self.langs = langs;
+        self
+    }
+}
+
+#[async_trait]
+impl FeedAlgorithm for QueryFeed {
+    fn rkey(&self) -> &str {
+        &self.rkey
+    }
+
+    async fn posts(&self, conn: &Graph, req: &FeedRequest) -> Result<Vec<FeedPost>, neo4rs::Error> {
+        let langs = if self.langs.is_empty() {
+            &req.langs
+        } else {
+            &self.langs
+        };
+        graph::get_feed_posts(
+            conn,
+            self.query,
+            &req.requester,
+            req.limit,
+            req.before.clone(),
+            langs,
+        )
+        .await
+    }
+}
+
+// Posts from whoever is on a list, the same for everyone who asks
+pub struct ListFeed {
+    rkey: String,
+    list: AtUri,
+    langs: Vec<String>,
+}
+
+impl ListFeed {
+    pub fn new(rkey: &str, list: AtUri) -> Self {
+        Self {
+            rkey: rkey.to_owned(),
+            list,
+            langs: Vec::new(),
+        }
+    }
+
+    pub fn with_langs(mut self, langs: Vec<String>) -> Self {
+        self.langs = langs;
+        self
+    }
+}
+
+#[async_trait]
+impl FeedAlgorithm for ListFeed {
+    fn rkey(&self) -> &str {
+        &self.rkey
+    }
+
+    async fn posts(&self, conn: &Graph, req: &FeedRequest) -> Result<Vec<FeedPost>, neo4rs::Error> {
+        let langs = if self.langs.is_empty() {
+            &req.langs
+        } else {
+            &self.langs
+        };
+        graph::get_list_posts(conn, &self.list, req.limit, req.before.clone(), langs).await
+    }
+}
+
+// Feeds in the order they are advertised by describeFeedGenerator
+#[derive(Default)]
+pub struct FeedRegistry {
+    feeds: Vec<Box<dyn FeedAlgorithm>>,
+}
+
+impl FeedRegistry {
+    pub fn new() -> Self {
+        let mut langs = feed_langs_from_env();
+        let mut registry = Self::default();
+        let feeds = [
+            (FOLLOWING_RKEY, queries::GET_FOLLOW_POSTS),
+            (FRIENDS_OF_FRIENDS_RKEY, queries::GET_2ND_DEG_FOLLOW_POSTS),
+            (
+                POPULAR_WITH_FRIENDS_RKEY,
+                queries::GET_POPULAR_WITH_FRIENDS_POSTS,
+            ),
+            (STARTER_PACK_RKEY, queries::GET_STARTER_PACK_POSTS),
+        ];
+        for (rkey, query) in feeds {
+            let feed_langs = langs.remove(rkey).unwrap_or_default();
+            registry.register(QueryFeed::new(rkey, query).with_langs(feed_langs));
+        }
+        let builtin = feeds.map(|(rkey, _)| rkey);
+        for (rkey, list) in list_feeds_from_env(&builtin) {
+            let feed_langs = langs.remove(&rkey).unwrap_or_default();
+            println!("Serving list {list} as feed {rkey}");
+            registry.register(ListFeed::new(&rkey, list).with_langs(feed_langs));
+        }
+        for rkey in langs.keys() {
+            println!("FEED_LANGUAGES has languages for unknown feed {rkey}");
+        }
+        registry
+    }
+
+    pub fn register(&mut self, feed: impl FeedAlgorithm + 'static) {
+        if self.get(feed.rkey()).is_some() {
+            panic!("feed {} registered twice", feed.rkey());
+        }
+        self.feeds.push(Box::new(feed));
+    }
+
+    pub fn get(&self, rkey: &str) -> Option<&dyn FeedAlgorithm> {
+        self.feeds
+            .iter()
+            .find(|f| f.rkey() == rkey)
+            .map(|f| f.as_ref())
+    }
+
+    pub fn rkeys(&self) -> impl Iterator<Item = &str> {
+        self.feeds.iter().map(|f| f.rkey())
+    }
+}
+
+// FEED_LANGUAGES=<rkey>=<lang>,<lang>;<rkey>=<lang> pins feeds to a set of languages
+fn feed_langs_from_env() -> HashMap<String, Vec<String>> {
+    let raw = env::var("FEED_LANGUAGES").unwrap_or_default();
+    let mut langs = HashMap::new();
+    for entry in raw.split(';').filter(|e| !e.trim().is_empty()) {
+        match entry.split_once('=') {
+            Some((rkey, l)) => {
+                langs.insert(rkey.trim().to_owned(), lang::normalize_all(l.split(',')));
+            }
+            None => println!("Ignoring malformed FEED_LANGUAGES entry {entry}"),
+        }
+    }
+    langs
+}
+
+// LIST_FEEDS=<rkey>=<list at-uri>;<rkey>=<list at-uri> publishes each list as its own feed.
+// rkeys already in `taken` or earlier in the list are skipped, register would panic on them
+fn list_feeds_from_env(taken: &[&str]) -> Vec<(String, AtUri)> {
+    let raw = env::var("LIST_FEEDS").unwrap_or_default();
+    let mut feeds = Vec::new();
+    for entry in raw.split(';').filter(|e| !e.trim().is_empty()) {
+        let (rkey, uri) = match entry.split_once('=') {
+            Some((rkey, uri)) if !rkey.trim().is_empty() => (rkey.trim(), uri.trim()),
+            _ => {
+                println!("Ignoring malformed LIST_FEEDS entry {entry}");
+                continue;
+            }
+        };
+        if taken.contains(&rkey) || feeds.iter().any(|(r, _)| r == rkey) {
+            println!("Ignoring LIST_FEEDS entry {entry}, feed {rkey} already exists");
+            continue;
+        }
+        match AtUri::parse(uri) {
+            Ok(list) if list.collection == "app.bsky.graph.list" => {
+                feeds.push((rkey.to_owned(), list))
+            }
+            _ => println!("Ignoring LIST_FEEDS entry {entry}, {uri} is not a list"),
+        }
+    }
+    feeds
+}
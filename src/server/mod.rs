@@ -1,7 +1,7 @@
 use axum::{
-    extract::{Query, State},
-    http::Method,
-    routing::get,
+    extract::{rejection::JsonRejection, Query, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    routing::{get, post},
     Json, Router,
 };
 use axum_extra::{
@@ -9,23 +9,54 @@ use axum_extra::{
     TypedHeader,
 };
 
-use neo4rs::{ConfigBuilder, Graph};
+use neo4rs::Graph;
 use std::{collections::HashMap, env, net::SocketAddr, sync::Arc};
-use tokio::{net::TcpListener, sync::mpsc::Sender};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc::Sender, watch, Mutex},
+};
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::common::FetchMessage;
+use crate::common::{lang, AtUri, FetchMessage};
+use crate::graph::{self, Feedback};
+use chrono::Utc;
+use cursor::Cursor;
+use did::DidResolver;
+use feeds::{FeedRegistry, FeedRequest};
+use ranking::RankingWeights;
+use types::XrpcError;
 mod auth;
+mod cursor;
+pub mod did;
+mod feeds;
+mod ranking;
 mod types;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 100;
+
+const FEED_SKELETON_NSID: &str = "app.bsky.feed.getFeedSkeleton";
+const SEND_INTERACTIONS_NSID: &str = "app.bsky.feed.sendInteractions";
+const FEED_GENERATOR_COLLECTION: &str = "app.bsky.feed.generator";
+
 struct StateStruct {
     send_chan: Sender<FetchMessage>,
     inner: Graph,
+    // Shared with ingest, taken around any write we make
+    write_lock: Arc<Mutex<()>>,
+    resolver: Arc<dyn DidResolver>,
+    service_did: String,
+    feeds: FeedRegistry,
+    ranking: RankingWeights,
 }
 
 pub async fn serve(
     chan: Sender<FetchMessage>,
     inner: Graph,
+    write_lock: Arc<Mutex<()>>,
+    resolver: Arc<dyn DidResolver>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let cors = CorsLayer::new()
         .allow_methods([
@@ -37,14 +68,28 @@ pub async fn serve(
         ])
         .allow_origin(Any);
 
+    let service_did = env::var("FEEDGEN_SERVICE_DID").unwrap_or_default();
+    if service_did.is_empty() {
+        println!("FEEDGEN_SERVICE_DID not set, every feed request will be rejected");
+    }
+
     let state = StateStruct {
         send_chan: chan.clone(),
         inner,
+        write_lock,
+        resolver,
+        service_did,
+        feeds: FeedRegistry::new(),
+        ranking: RankingWeights::from_env(),
     };
     let state = Arc::new(state);
     let router = Router::new()
         .route("/xrpc/app.bsky.feed.getFeedSkeleton", get(index))
         .route("/xrpc/app.bsky.feed.describeFeedGenerator", get(describe))
+        .route(
+            "/xrpc/app.bsky.feed.sendInteractions",
+            post(send_interactions),
+        )
         .route("/.well-known/did.json", get(well_known))
         .layer(ServiceBuilder::new().layer(cors))
         .with_state(state);
@@ -52,34 +97,237 @@ pub async fn serve(
     let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
     let tcp = TcpListener::bind(&addr).await.unwrap();
 
-    axum::serve(tcp, router).await.unwrap();
+    // Stops accepting once shutdown is flagged, but lets in-flight requests finish
+    axum::serve(tcp, router)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|s| *s).await;
+            println!("Stopping web listener");
+        })
+        .await
+        .unwrap();
     Ok(())
 }
 
 async fn index(
     Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    State(_state): State<Arc<StateStruct>>,
-) -> Result<types::Response, ()> {
-    let auth;
-    println!("{:?}", params);
-    match bearer {
-        Some(s) => {
-            auth = s.0 .0.token();
-            auth::verify_jwt(auth, &"".to_owned()).unwrap();
+    State(state): State<Arc<StateStruct>>,
+) -> Result<types::Response, XrpcError> {
+    let requester = authenticate(bearer, &state, FEED_SKELETON_NSID).await?;
+
+    // First time we see someone we need their follow graph, the worker skips anyone it already has
+    if let Err(e) = state.send_chan.try_send(FetchMessage {
+        did: requester.clone(),
+    }) {
+        println!("Could not queue follow graph fetch for {requester}: {e}");
+    }
+
+    let feed = match params.get("feed") {
+        Some(f) => match feed_rkey(f).and_then(|rkey| state.feeds.get(rkey)) {
+            Some(feed) => feed,
+            None => {
+                return Err(XrpcError::new(
+                    StatusCode::BAD_REQUEST,
+                    "UnknownFeed",
+                    format!("unknown feed {f}"),
+                ))
+            }
+        },
+        None => {
+            return Err(XrpcError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                "feed is required",
+            ))
+        }
+    };
+
+    let limit = match params.get("limit") {
+        Some(l) => match l.parse::<i64>() {
+            Ok(l) if (1..=MAX_LIMIT).contains(&l) => l,
+            _ => {
+                return Err(XrpcError::new(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidRequest",
+                    format!("limit must be between 1 and {MAX_LIMIT}"),
+                ))
+            }
+        },
+        None => DEFAULT_LIMIT,
+    };
+
+    let before = match params.get("cursor") {
+        Some(c) => match Cursor::decode(c) {
+            Ok(c) => Some((c.timestamp, c.rkey)),
+            Err(e) => return Err(XrpcError::new(StatusCode::BAD_REQUEST, "InvalidRequest", e)),
+        },
+        None => None,
+    };
+
+    // The AppView passes along the languages the user has picked in their settings
+    let langs = match headers.get(header::ACCEPT_LANGUAGE) {
+        Some(h) => lang::from_accept_language(h.to_str().unwrap_or_default()),
+        None => Vec::new(),
+    };
+
+    let req = FeedRequest {
+        requester,
+        limit,
+        before,
+        langs,
+    };
+    let mut posts = match feed.posts(&state.inner, &req).await {
+        Ok(p) => p,
+        Err(e) => {
+            println!(
+                "Error fetching feed {} for {}: {e}",
+                feed.rkey(),
+                req.requester
+            );
+            return Err(XrpcError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalServerError",
+                "error fetching feed",
+            ));
         }
-        None => {}
+    };
+
+    // A short page means we've run out, so dont hand out a cursor for an empty next page
+    let cursor = match posts.last() {
+        Some(last) if posts.len() as i64 == limit => Some(
+            Cursor {
+                timestamp: last.timestamp,
+                rkey: last.rkey.clone(),
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+
+    // Feeds page by time, so ranking only reorders within the page and the cursor above still holds.
+    // Better an unranked page than none if the counts cant be had
+    let feedback = match graph::get_author_feedback(&state.inner, &req.requester, &posts).await {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Error fetching feedback for {}: {e}", req.requester);
+            HashMap::new()
+        }
+    };
+    match graph::get_engagement(&state.inner, &posts).await {
+        Ok(engagement) => state.ranking.rank(
+            &mut posts,
+            &engagement,
+            &feedback,
+            Utc::now().timestamp_micros(),
+        ),
+        Err(e) => println!("Error fetching engagement for feed {}: {e}", feed.rkey()),
     }
 
     Ok(types::Response {
-        cursor: None,
-        feed: vec![types::Post {
-            post: "at://did:plc:zxs7h3vusn4chpru4nvzw5sj/app.bsky.feed.post/3lbdbqqdxxc2w"
-                .to_owned(),
-        }],
+        cursor,
+        feed: posts
+            .into_iter()
+            .map(|p| types::Post {
+                post: graph::get_post_uri(p.did, p.rkey),
+                reason: p.repost.map(|r| types::Reason::Repost {
+                    repost: r.to_string(),
+                }),
+                // <feed rkey>:<whatever the query said>, so interactions can be tied back to both
+                feed_context: Some(match p.context {
+                    Some(c) => format!("{}:{c}", feed.rkey()),
+                    None => feed.rkey().to_owned(),
+                }),
+            })
+            .collect(),
     })
 }
 
+async fn send_interactions(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    State(state): State<Arc<StateStruct>>,
+    body: Result<Json<types::SendInteractions>, JsonRejection>,
+) -> Result<Json<types::SendInteractionsResp>, XrpcError> {
+    let requester = authenticate(bearer, &state, SEND_INTERACTIONS_NSID).await?;
+    let body = match body {
+        Ok(Json(b)) => b,
+        Err(e) => {
+            return Err(XrpcError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                e.body_text(),
+            ))
+        }
+    };
+
+    // Only posts & events we do something with, the rest is dropped quietly
+    let interactions = body
+        .interactions
+        .into_iter()
+        .filter_map(|i| {
+            let post = AtUri::parse(&i.item?).ok().filter(|u| u.is_post())?;
+            let feedback = feedback(&i.event?)?;
+            Some(graph::Interaction {
+                post,
+                feedback,
+                context: i.feed_context,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if !interactions.is_empty() {
+        if let Err(e) =
+            graph::add_interactions(&state.inner, &state.write_lock, &requester, &interactions)
+                .await
+        {
+            println!("Error storing interactions for {requester}: {e}");
+            return Err(XrpcError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalServerError",
+                "error storing interactions",
+            ));
+        }
+    }
+
+    Ok(Json(types::SendInteractionsResp {}))
+}
+
+// app.bsky.feed.defs#<event>
+fn feedback(event: &str) -> Option<Feedback> {
+    match event.strip_prefix("app.bsky.feed.defs#")? {
+        "requestLess" => Some(Feedback::Less),
+        "requestMore" => Some(Feedback::More),
+        "interactionSeen" => Some(Feedback::Seen),
+        "clickthroughItem"
+        | "clickthroughAuthor"
+        | "clickthroughReposter"
+        | "clickthroughEmbed" => Some(Feedback::Clicked),
+        _ => None,
+    }
+}
+
+// The requester's DID, if they sent a valid service token for `nsid`
+async fn authenticate(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    state: &StateStruct,
+    nsid: &str,
+) -> Result<String, XrpcError> {
+    match bearer {
+        Some(s) => auth::verify_jwt(
+            s.0 .0.token(),
+            &state.service_did,
+            nsid,
+            state.resolver.as_ref(),
+        )
+        .await
+        .map_err(|e| {
+            println!("Rejected {nsid} request: {e}");
+            XrpcError::from(e)
+        }),
+        None => Err(auth::AuthError::Missing.into()),
+    }
+}
+
 // This needs to be exposed on port 443 too
 async fn well_known() -> Result<Json<types::WellKnown>, ()> {
     match env::var("FEEDGEN_SERVICE_DID") {
@@ -87,7 +335,7 @@ async fn well_known() -> Result<Json<types::WellKnown>, ()> {
             let hostname = env::var("FEEDGEN_HOSTNAME").unwrap_or("".into());
             if !service_did.ends_with(hostname.as_str()) {
                 println!("service_did does not end with hostname");
-                return Err(());
+                Err(())
             } else {
                 let known_service = types::KnownService {
                     id: "#bsky_fg".to_owned(),
@@ -99,24 +347,41 @@ async fn well_known() -> Result<Json<types::WellKnown>, ()> {
                     id: service_did,
                     service: vec![known_service],
                 };
-                return Ok(Json(result));
+                Ok(Json(result))
             }
         }
         Err(_) => {
             println!("service_did not found");
-            return Err(());
+            Err(())
         }
     }
 }
 
-async fn describe() -> Result<Json<types::Describe>, ()> {
+async fn describe(State(state): State<Arc<StateStruct>>) -> Result<Json<types::Describe>, ()> {
     let hostname = env::var("FEEDGEN_HOSTNAME").unwrap();
     let dezscribe = types::Describe {
         did: format!("did:web:{hostname}"),
-        feeds: vec![types::Feed {
-            uri: format!("at://did:web:{hostname}/app.bsky.feed.generator/m1k_test_feed"),
-        }],
+        feeds: state
+            .feeds
+            .rkeys()
+            .map(|rkey| types::Feed {
+                uri: format!("at://did:web:{hostname}/{FEED_GENERATOR_COLLECTION}/{rkey}"),
+            })
+            .collect(),
     };
 
     Ok(Json(dezscribe))
 }
+
+// at://<publisher>/app.bsky.feed.generator/<rkey>
+fn feed_rkey(uri: &str) -> Option<&str> {
+    let mut parts = uri.strip_prefix("at://")?.split('/');
+    let _publisher = parts.next()?;
+    if parts.next()? != FEED_GENERATOR_COLLECTION {
+        return None;
+    }
+    match (parts.next(), parts.next()) {
+        (Some(rkey), None) if !rkey.is_empty() => Some(rkey),
+        _ => None,
+    }
+}
@@ -0,0 +1,223 @@
+use std::{cmp::Ordering, collections::HashMap, env};
+
+use crate::graph::{Engagement, FeedPost};
+
+const MICROS_PER_HOUR: f64 = 60.0 * 60.0 * 1_000_000.0;
+// Keeps brand new posts from dividing by ~0
+const AGE_OFFSET_HOURS: f64 = 2.0;
+
+// How much each kind of engagement is worth, and how hard age drags a post down.
+// score = (1 + weighted engagement) / (age in hours + 2) ^ gravity
+// then multiplied by `feedback` for authors the requester asked for more of, divided for less.
+// Reply restricted posts count as `gated_hours` older, theres no joining in on them
+#[derive(Debug, Clone)]
+pub struct RankingWeights {
+    pub like: f64,
+    pub repost: f64,
+    pub reply: f64,
+    pub quote: f64,
+    pub gravity: f64,
+    pub feedback: f64,
+    pub gated_hours: f64,
+}
+
+impl Default for RankingWeights {
+    fn default() -> Self {
+        Self {
+            like: 1.0,
+            repost: 2.0,
+            reply: 1.5,
+            quote: 2.0,
+            gravity: 1.8,
+            feedback: 2.0,
+            gated_hours: 1.0,
+        }
+    }
+}
+
+impl RankingWeights {
+    // RANK_LIKE_WEIGHT, RANK_REPOST_WEIGHT, RANK_REPLY_WEIGHT, RANK_QUOTE_WEIGHT, RANK_GRAVITY,
+    // RANK_FEEDBACK_WEIGHT & RANK_GATED_HOURS override the defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let var = |name: &str, default: f64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|v| v.is_finite() && *v >= 0.0)
+                .unwrap_or(default)
+        };
+        Self {
+            like: var("RANK_LIKE_WEIGHT", default.like),
+            repost: var("RANK_REPOST_WEIGHT", default.repost),
+            reply: var("RANK_REPLY_WEIGHT", default.reply),
+            quote: var("RANK_QUOTE_WEIGHT", default.quote),
+            gravity: var("RANK_GRAVITY", default.gravity),
+            // Under 1 would flip more & less around, 1 turns feedback off
+            feedback: var("RANK_FEEDBACK_WEIGHT", default.feedback).max(1.0),
+            gated_hours: var("RANK_GATED_HOURS", default.gated_hours),
+        }
+    }
+
+    // `posted` is when the post was made, not the feed's ordering key
+    pub fn score(&self, engagement: &Engagement, posted: i64, now: i64) -> f64 {
+        let points = 1.0
+            + self.like * engagement.likes as f64
+            + self.repost * engagement.reposts as f64
+            + self.reply * engagement.replies as f64
+            + self.quote * engagement.quotes as f64;
+        // Anything from the future is just new
+        let age = (now - posted).max(0) as f64 / MICROS_PER_HOUR;
+        points / (age + AGE_OFFSET_HOURS).powf(self.gravity)
+    }
+
+    // Best first. Posts missing from `engagement` count as having none, authors missing from
+    // `feedback` as having had none.
+    // This only ever shuffles the one page it's handed. The feed query already picked those posts
+    // by time & the cursor comes off the last of them, so nothing on the next page can outrank
+    // anything on this one
+    pub fn rank(
+        &self,
+        posts: &mut [FeedPost],
+        engagement: &HashMap<(String, String), Engagement>,
+        feedback: &HashMap<String, i64>,
+        now: i64,
+    ) {
+        let none = Engagement::default();
+        let score = |p: &FeedPost| {
+            let e = engagement
+                .get(&(p.did.clone(), p.rkey.clone()))
+                .unwrap_or(&none);
+            let signal = feedback.get(&p.did).copied().unwrap_or_default().signum() as i32;
+            let posted = if p.gated {
+                p.posted - (self.gated_hours * MICROS_PER_HOUR) as i64
+            } else {
+                p.posted
+            };
+            self.score(e, posted, now) * self.feedback.powi(signal)
+        };
+        // Stable, so ties keep the feed's own order
+        posts.sort_by(|a, b| score(b).partial_cmp(&score(a)).unwrap_or(Ordering::Equal));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = MICROS_PER_HOUR as i64;
+    const NOW: i64 = 1_000 * HOUR;
+
+    fn post(did: &str, rkey: &str, posted: i64) -> FeedPost {
+        FeedPost {
+            did: did.to_owned(),
+            rkey: rkey.to_owned(),
+            timestamp: posted,
+            posted,
+            repost: None,
+            context: None,
+            gated: false,
+        }
+    }
+
+    fn order(posts: &[FeedPost]) -> Vec<&str> {
+        posts.iter().map(|p| p.rkey.as_str()).collect()
+    }
+
+    fn engagement(likes: i64, reposts: i64, replies: i64, quotes: i64) -> Engagement {
+        Engagement {
+            likes,
+            reposts,
+            replies,
+            quotes,
+        }
+    }
+
+    fn key(p: &FeedPost) -> (String, String) {
+        (p.did.clone(), p.rkey.clone())
+    }
+
+    #[test]
+    fn score_is_weighted_engagement_over_age() {
+        let w = RankingWeights::default();
+        let e = engagement(2, 1, 2, 1);
+        // 1 + 2*1 + 1*2 + 2*1.5 + 1*2 = 10, four hours old
+        let want = 10.0 / (4.0 + AGE_OFFSET_HOURS).powf(w.gravity);
+        assert!((w.score(&e, NOW - 4 * HOUR, NOW) - want).abs() < 1e-12);
+    }
+
+    #[test]
+    fn score_treats_the_future_as_now() {
+        let w = RankingWeights::default();
+        let e = engagement(0, 0, 0, 0);
+        assert_eq!(w.score(&e, NOW + HOUR, NOW), w.score(&e, NOW, NOW));
+    }
+
+    #[test]
+    fn weights_decide_which_engagement_counts_most() {
+        let liked = post("did:plc:a", "liked", NOW);
+        let reposted = post("did:plc:b", "reposted", NOW);
+        let counts = HashMap::from([
+            (key(&liked), engagement(1, 0, 0, 0)),
+            (key(&reposted), engagement(0, 1, 0, 0)),
+        ]);
+
+        let mut posts = vec![liked.clone(), reposted.clone()];
+        RankingWeights::default().rank(&mut posts, &counts, &HashMap::new(), NOW);
+        assert_eq!(order(&posts), ["reposted", "liked"]);
+
+        let likes_first = RankingWeights {
+            like: 5.0,
+            ..Default::default()
+        };
+        let mut posts = vec![reposted, liked];
+        likes_first.rank(&mut posts, &counts, &HashMap::new(), NOW);
+        assert_eq!(order(&posts), ["liked", "reposted"]);
+    }
+
+    // A repost bumps timestamp to when it was reposted, but the post is still as old as it was
+    #[test]
+    fn decay_uses_when_it_was_posted() {
+        let mut bumped = post("did:plc:a", "bumped", NOW - 10 * HOUR);
+        bumped.timestamp = NOW;
+        let fresh = post("did:plc:b", "fresh", NOW - HOUR);
+        let mut posts = vec![bumped, fresh];
+        RankingWeights::default().rank(&mut posts, &HashMap::new(), &HashMap::new(), NOW);
+        assert_eq!(order(&posts), ["fresh", "bumped"]);
+    }
+
+    #[test]
+    fn feedback_multiplies_by_author() {
+        let w = RankingWeights::default();
+        let e = engagement(3, 0, 0, 0);
+        let newer = post("did:plc:a", "newer", NOW - HOUR);
+        let older = post("did:plc:b", "older", NOW - 2 * HOUR);
+        let counts = HashMap::from([(key(&newer), e.clone()), (key(&older), e.clone())]);
+
+        // Only the sign counts, however many times they asked
+        let more = HashMap::from([("did:plc:b".to_owned(), 7)]);
+        let mut posts = vec![newer.clone(), older.clone()];
+        w.rank(&mut posts, &counts, &more, NOW);
+        assert_eq!(order(&posts), ["older", "newer"]);
+
+        let less = HashMap::from([("did:plc:a".to_owned(), -1)]);
+        let mut posts = vec![newer.clone(), older.clone()];
+        w.rank(&mut posts, &counts, &less, NOW);
+        assert_eq!(order(&posts), ["older", "newer"]);
+
+        // No feedback, no change
+        let mut posts = vec![older, newer];
+        w.rank(&mut posts, &counts, &HashMap::new(), NOW);
+        assert_eq!(order(&posts), ["newer", "older"]);
+    }
+
+    #[test]
+    fn gated_posts_rank_as_older() {
+        let w = RankingWeights::default();
+        let mut gated = post("did:plc:a", "gated", NOW - HOUR / 2);
+        gated.gated = true;
+        let mut posts = vec![gated, post("did:plc:b", "open", NOW - HOUR)];
+        w.rank(&mut posts, &HashMap::new(), &HashMap::new(), NOW);
+        assert_eq!(order(&posts), ["open", "gated"]);
+    }
+}
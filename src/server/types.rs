@@ -1,5 +1,4 @@
 use axum::{http::StatusCode, response::IntoResponse, Json};
-use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
@@ -14,11 +13,68 @@ impl IntoResponse for Response {
     }
 }
 
+// XRPC errors are a JSON body of { error, message } alongside the status code
+#[derive(Debug, Serialize)]
+pub struct XrpcError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub error: String,
+    pub message: String,
+}
+
+impl XrpcError {
+    pub fn new(status: StatusCode, error: &str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: error.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for XrpcError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
 pub struct Post {
     pub post: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
+    // Handed back to us by the client with any interactions on this post
+    #[serde(rename = "feedContext", skip_serializing_if = "Option::is_none")]
+    pub feed_context: Option<String>,
+}
+
+// Why a post is in the feed, for the client to show alongside it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "$type")]
+pub enum Reason {
+    #[serde(rename = "app.bsky.feed.defs#skeletonReasonRepost")]
+    Repost { repost: String },
+}
+
+// app.bsky.feed.sendInteractions input
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SendInteractions {
+    pub interactions: Vec<Interaction>,
+}
+
+// Everything is optional in the lexicon, items missing what we need get skipped
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Interaction {
+    pub item: Option<String>,
+    pub event: Option<String>,
+    pub feed_context: Option<String>,
 }
 
+// The output is an empty object
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SendInteractionsResp {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KnownService {
     #[serde(rename = "id")]